@@ -0,0 +1,16 @@
+DROP TABLE winner_audits;
+
+-- Rebuild the table rather than dropping the column, DROP COLUMN needs SQLite 3.35
+CREATE TABLE winners_without_deleted (
+  id VARCHAR NOT NULL PRIMARY KEY,
+  first_name VARCHAR NOT NULL,
+  last_name VARCHAR NOT NULL,
+  event_id VARCHAR NOT NULL
+);
+
+INSERT INTO winners_without_deleted (id, first_name, last_name, event_id)
+  SELECT id, first_name, last_name, event_id FROM winners;
+
+DROP TABLE winners;
+
+ALTER TABLE winners_without_deleted RENAME TO winners
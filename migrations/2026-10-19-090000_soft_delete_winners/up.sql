@@ -0,0 +1,13 @@
+ALTER TABLE winners ADD COLUMN deleted BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE winner_audits (
+  id VARCHAR NOT NULL PRIMARY KEY,
+  winner_id VARCHAR NOT NULL REFERENCES winners (id),
+  action VARCHAR NOT NULL,
+  author VARCHAR NOT NULL,
+  previous_first_name VARCHAR,
+  previous_last_name VARCHAR,
+  first_name VARCHAR,
+  last_name VARCHAR,
+  changed_at TIMESTAMP NOT NULL
+)
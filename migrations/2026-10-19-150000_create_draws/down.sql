@@ -0,0 +1,2 @@
+DROP TABLE draw_results;
+DROP TABLE draws;
@@ -0,0 +1,17 @@
+CREATE TABLE draws (
+  id VARCHAR NOT NULL PRIMARY KEY,
+  event_id VARCHAR NOT NULL,
+  author VARCHAR NOT NULL,
+  seed BIGINT,
+  prize VARCHAR,
+  drawn_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE draw_results (
+  id VARCHAR NOT NULL PRIMARY KEY,
+  draw_id VARCHAR NOT NULL REFERENCES draws(id),
+  position INTEGER NOT NULL,
+  first_name VARCHAR NOT NULL,
+  last_name VARCHAR NOT NULL,
+  winner_id VARCHAR REFERENCES winners(id)
+);
@@ -0,0 +1,66 @@
+-- Rebuild the tables rather than dropping the columns, DROP COLUMN needs SQLite 3.35.
+-- The results and audits reference the rebuilt tables, they are kept aside then created again
+CREATE TABLE winners_without_tenant (
+  id VARCHAR NOT NULL PRIMARY KEY,
+  first_name VARCHAR NOT NULL,
+  last_name VARCHAR NOT NULL,
+  event_id VARCHAR NOT NULL,
+  deleted BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+INSERT INTO winners_without_tenant (id, first_name, last_name, event_id, deleted)
+  SELECT id, first_name, last_name, event_id, deleted FROM winners;
+
+CREATE TABLE draws_without_tenant (
+  id VARCHAR NOT NULL PRIMARY KEY,
+  event_id VARCHAR NOT NULL,
+  author VARCHAR NOT NULL,
+  seed BIGINT,
+  prize VARCHAR,
+  drawn_at TIMESTAMP NOT NULL
+);
+
+INSERT INTO draws_without_tenant (id, event_id, author, seed, prize, drawn_at)
+  SELECT id, event_id, author, seed, prize, drawn_at FROM draws;
+
+CREATE TABLE kept_winner_audits AS SELECT * FROM winner_audits;
+CREATE TABLE kept_draw_results AS SELECT * FROM draw_results;
+
+DROP TABLE winner_audits;
+DROP TABLE draw_results;
+DROP TABLE winners;
+DROP TABLE draws;
+
+ALTER TABLE winners_without_tenant RENAME TO winners;
+ALTER TABLE draws_without_tenant RENAME TO draws;
+
+CREATE TABLE winner_audits (
+  id VARCHAR NOT NULL PRIMARY KEY,
+  winner_id VARCHAR NOT NULL REFERENCES winners (id),
+  action VARCHAR NOT NULL,
+  author VARCHAR NOT NULL,
+  previous_first_name VARCHAR,
+  previous_last_name VARCHAR,
+  first_name VARCHAR,
+  last_name VARCHAR,
+  changed_at TIMESTAMP NOT NULL
+);
+
+INSERT INTO winner_audits (id, winner_id, action, author, previous_first_name, previous_last_name, first_name, last_name, changed_at)
+  SELECT id, winner_id, action, author, previous_first_name, previous_last_name, first_name, last_name, changed_at FROM kept_winner_audits;
+
+CREATE TABLE draw_results (
+  id VARCHAR NOT NULL PRIMARY KEY,
+  draw_id VARCHAR NOT NULL REFERENCES draws(id),
+  position INTEGER NOT NULL,
+  first_name VARCHAR NOT NULL,
+  last_name VARCHAR NOT NULL,
+  winner_id VARCHAR REFERENCES winners(id)
+);
+
+INSERT INTO draw_results (id, draw_id, position, first_name, last_name, winner_id)
+  SELECT id, draw_id, position, first_name, last_name, winner_id FROM kept_draw_results;
+
+DROP TABLE kept_winner_audits;
+
+DROP TABLE kept_draw_results
@@ -0,0 +1,3 @@
+ALTER TABLE winners ADD COLUMN tenant VARCHAR NOT NULL DEFAULT 'default';
+
+ALTER TABLE draws ADD COLUMN tenant VARCHAR NOT NULL DEFAULT 'default'
@@ -0,0 +1,16 @@
+-- Rebuild the table rather than dropping the column, DROP COLUMN needs SQLite 3.35
+CREATE TABLE api_tokens_without_tenant (
+  id VARCHAR NOT NULL PRIMARY KEY,
+  name VARCHAR NOT NULL,
+  token_hash VARCHAR NOT NULL UNIQUE,
+  role VARCHAR NOT NULL,
+  created_at TIMESTAMP NOT NULL,
+  revoked BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+INSERT INTO api_tokens_without_tenant (id, name, token_hash, role, created_at, revoked)
+  SELECT id, name, token_hash, role, created_at, revoked FROM api_tokens;
+
+DROP TABLE api_tokens;
+
+ALTER TABLE api_tokens_without_tenant RENAME TO api_tokens
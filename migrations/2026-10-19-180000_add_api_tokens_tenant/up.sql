@@ -0,0 +1 @@
+ALTER TABLE api_tokens ADD COLUMN tenant VARCHAR NOT NULL DEFAULT 'default'
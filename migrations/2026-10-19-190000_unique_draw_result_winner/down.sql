@@ -0,0 +1 @@
+DROP INDEX draw_results_winner_id
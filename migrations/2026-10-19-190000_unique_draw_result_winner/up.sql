@@ -0,0 +1 @@
+CREATE UNIQUE INDEX draw_results_winner_id ON draw_results (winner_id)
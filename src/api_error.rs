@@ -0,0 +1,167 @@
+//! JSON error bodies of the api, every error response carries the id of its request
+use actix_web::{error, http, HttpRequest, HttpResponse, Result};
+use actix_web::middleware::{Middleware, Response, Started};
+use schemars::JsonSchema;
+use serde_json;
+use std::fmt;
+use uuid;
+use LotteryError;
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Body of the error responses
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+    pub details: Option<String>,
+    pub request_id: Option<String>,
+}
+
+impl ErrorBody {
+    pub fn new(code: &str, message: &str) -> ErrorBody {
+        ErrorBody { code: code.to_owned(), message: message.to_owned(), details: None, request_id: None }
+    }
+}
+
+impl<'a> From<&'a LotteryError> for ErrorBody {
+    fn from(error: &'a LotteryError) -> ErrorBody {
+        let code = match *error {
+            LotteryError::InvalidParameter { .. } => "invalid_parameter",
+            LotteryError::InvalidRequest { .. } => "invalid_request",
+            LotteryError::NoEventAvailable => "no_event_available",
+            LotteryError::DatabaseUnavailable { .. } => "database_unavailable",
+            LotteryError::WinnerNotFound { .. } => "winner_not_found",
+            LotteryError::TokenNotFound { .. } => "token_not_found",
+            LotteryError::TenantNotFound { .. } => "tenant_not_found",
+            LotteryError::DrawResultNotFound { .. } => "draw_result_not_found",
+            LotteryError::AlreadyRecorded { .. } => "already_recorded",
+            LotteryError::UnknownAttendee { .. } => "unknown_attendee",
+            LotteryError::NotAllowed { .. } => "forbidden",
+            LotteryError::InvalidConfiguration { .. } => "invalid_configuration",
+            LotteryError::DrawError { .. } => "draw_error",
+            LotteryError::UnexpectedError { .. } => "unexpected_error",
+        };
+        let details = match *error {
+            LotteryError::InvalidParameter { ref reason, .. } => Some(reason.clone()),
+            LotteryError::InvalidRequest { ref details } | LotteryError::InvalidConfiguration { ref details } => Some(details.clone()),
+            LotteryError::DatabaseUnavailable { ref cause } | LotteryError::DrawError { ref cause } | LotteryError::UnexpectedError { ref cause } =>
+                Some(cause.to_string()),
+            _ => None,
+        };
+        ErrorBody { code: code.to_owned(), message: error.to_string(), details, request_id: None }
+    }
+}
+
+impl error::ResponseError for LotteryError {
+    /// The request id is added by the `RequestId` middleware
+    fn error_response(&self) -> HttpResponse {
+        let status = match *self {
+            LotteryError::InvalidParameter { .. } | LotteryError::InvalidRequest { .. } | LotteryError::DrawError { .. } => http::StatusCode::BAD_REQUEST,
+            LotteryError::NoEventAvailable | LotteryError::DatabaseUnavailable { .. } => http::StatusCode::SERVICE_UNAVAILABLE,
+            LotteryError::WinnerNotFound { .. } | LotteryError::TokenNotFound { .. } | LotteryError::TenantNotFound { .. } | LotteryError::DrawResultNotFound { .. } =>
+                http::StatusCode::NOT_FOUND,
+            LotteryError::AlreadyRecorded { .. } => http::StatusCode::CONFLICT,
+            LotteryError::UnknownAttendee { .. } | LotteryError::InvalidConfiguration { .. } => http::StatusCode::UNPROCESSABLE_ENTITY,
+            LotteryError::NotAllowed { .. } => http::StatusCode::FORBIDDEN,
+            LotteryError::UnexpectedError { .. } => http::StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        HttpResponse::build(status).json(ErrorBody::from(self))
+    }
+}
+
+/// Error handler of the query and json extractors
+pub fn invalid_request<E: fmt::Display, S>(error: E, _req: &HttpRequest<S>) -> error::Error {
+    LotteryError::InvalidRequest { details: error.to_string() }.into()
+}
+
+/// Id of the request, set by the `RequestId` middleware
+#[derive(Clone)]
+struct RequestIdValue(String);
+
+pub fn request_id<S>(req: &HttpRequest<S>) -> Option<String> {
+    req.extensions().get::<RequestIdValue>().map(|id| id.0.clone())
+}
+
+/// Error response with the id of the request
+pub fn error_response<S>(req: &HttpRequest<S>, status: http::StatusCode, mut body: ErrorBody) -> HttpResponse {
+    body.request_id = request_id(req);
+    HttpResponse::build(status).json(body)
+}
+
+/// Ids from the clients or proxies are kept if they look sane, to follow a request across services
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Middleware giving an id to every request, sent back in the `X-Request-Id` header and the error bodies
+pub struct RequestId;
+
+impl<S> Middleware<S> for RequestId {
+    fn start(&self, req: &HttpRequest<S>) -> Result<Started> {
+        let id = req.headers().get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| is_valid_request_id(id))
+            .map(|id| id.to_owned())
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        req.extensions_mut().insert(RequestIdValue(id));
+        Ok(Started::Done)
+    }
+
+    fn response(&self, req: &HttpRequest<S>, mut resp: HttpResponse) -> Result<Response> {
+        if let Some(id) = request_id(req) {
+            let body = resp.error()
+                .and_then(|error| error.as_fail().downcast_ref::<LotteryError>())
+                .map(|error| ErrorBody { request_id: Some(id.clone()), ..ErrorBody::from(error) });
+            if let Some(body) = body {
+                resp.set_body(serde_json::to_vec(&body)?);
+            }
+            resp.headers_mut().insert(REQUEST_ID_HEADER, http::header::HeaderValue::from_str(&id)?);
+        }
+        Ok(Response::Done(resp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::Body;
+    use actix_web::test::TestRequest;
+
+    fn body(resp: &HttpResponse) -> ErrorBody {
+        match *resp.body() {
+            Body::Binary(ref binary) => serde_json::from_slice(binary.as_ref()).unwrap(),
+            _ => panic!("Unexpected body"),
+        }
+    }
+
+    #[test]
+    fn test_error_body() {
+        let error = LotteryError::WinnerNotFound { id: "1".to_string() };
+        assert_eq!(ErrorBody::from(&error), ErrorBody { code: "winner_not_found".to_string(), message: "Winner 1 not found".to_string(), details: None, request_id: None });
+
+        let error = LotteryError::InvalidRequest { details: "missing field `nb`".to_string() };
+        let resp = error::ResponseError::error_response(&error);
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        assert_eq!(body(&resp).details, Some("missing field `nb`".to_string()));
+    }
+
+    #[test]
+    fn test_request_id() {
+        let req = TestRequest::with_header(REQUEST_ID_HEADER, "a5b0-41c2").finish();
+        RequestId.start(&req).unwrap();
+        let resp: HttpResponse = error::Error::from(LotteryError::NoEventAvailable).into();
+        let resp = match RequestId.response(&req, resp).unwrap() {
+            Response::Done(resp) => resp,
+            _ => panic!("Unexpected response"),
+        };
+        assert_eq!(resp.headers().get(REQUEST_ID_HEADER).unwrap(), "a5b0-41c2");
+        assert_eq!(body(&resp).request_id, Some("a5b0-41c2".to_string()));
+        assert_eq!(body(&resp).code, "no_event_available");
+
+        // Generated when missing or invalid
+        let req = TestRequest::with_header(REQUEST_ID_HEADER, "a5b0 41c2").finish();
+        RequestId.start(&req).unwrap();
+        assert_eq!(request_id(&req).map(|id| id.len()), Some(36));
+    }
+}
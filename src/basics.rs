@@ -1,3 +1,5 @@
+// Samples used during the presentation, not called by the application
+#![allow(dead_code, clippy::manual_checked_ops)]
 
 fn there_is_no_null(numerator: u8, denominator: u8) -> Option<u8> {
     if denominator == 0 {
@@ -0,0 +1,258 @@
+//! Live events, published to every connected screen
+//!
+//! Events are numbered and the last ones are kept so reconnecting clients can catch up.
+use actix::{Actor, Context, Handler, Message, Recipient, Addr};
+use eventbrite::{Event, Profile};
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use repository::Winner;
+use std::collections::VecDeque;
+
+/// Number of events kept for the clients reconnecting
+const REPLAY_BUFFER_SIZE: usize = 100;
+
+/// Event happening during the lottery
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LiveEvent {
+    /// Attendees count changed after a refresh from eventbrite
+    AttendeesUpdated {
+        count: usize
+    },
+    /// Current event changed on eventbrite
+    EventChanged {
+        event: Event
+    },
+    Started {
+        nb: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        prize: Option<String>,
+    },
+    WinnerRevealed {
+        position: usize,
+        winner: Profile,
+    },
+    Recorded {
+        winner: Winner
+    },
+    /// A recorded winner was removed, usually because the person was not in the room
+    Forfeited {
+        winner: Winner
+    },
+}
+
+impl LiveEvent {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            LiveEvent::AttendeesUpdated { .. } => "attendees_updated",
+            LiveEvent::EventChanged { .. } => "event_changed",
+            LiveEvent::Started { .. } => "started",
+            LiveEvent::WinnerRevealed { .. } => "winner_revealed",
+            LiveEvent::Recorded { .. } => "recorded",
+            LiveEvent::Forfeited { .. } => "forfeited",
+        }
+    }
+}
+
+/// Event sent to the subscribers with its sequence number
+#[derive(Debug, Clone, PartialEq)]
+pub struct Published {
+    pub id: u64,
+    pub event: LiveEvent,
+}
+
+impl Message for Published {
+    type Result = ();
+}
+
+/// Register an actor receiving every published event
+pub struct Subscribe(pub Recipient<Published>);
+
+impl Message for Subscribe {
+    type Result = ();
+}
+
+/// Register a stream receiving the events published after `last_event_id`, still in the buffer, then the new ones
+pub struct SubscribeStream {
+    pub last_event_id: Option<u64>,
+}
+
+impl Message for SubscribeStream {
+    type Result = Result<UnboundedReceiver<Published>, ()>;
+}
+
+pub struct Publish(pub LiveEvent);
+
+impl Message for Publish {
+    type Result = ();
+}
+
+enum Subscriber {
+    Actor(Recipient<Published>),
+    Stream(UnboundedSender<Published>),
+}
+
+impl Subscriber {
+    /// Deliver an event, false when the subscriber is disconnected
+    fn send(&self, published: Published) -> bool {
+        match *self {
+            Subscriber::Actor(ref recipient) => recipient.do_send(published).is_ok(),
+            Subscriber::Stream(ref sender) => sender.unbounded_send(published).is_ok(),
+        }
+    }
+}
+
+pub struct Broadcaster {
+    subscribers: Vec<Subscriber>,
+    history: VecDeque<Published>,
+    next_id: u64,
+}
+
+impl Default for Broadcaster {
+    fn default() -> Self {
+        Broadcaster { subscribers: Vec::new(), history: VecDeque::with_capacity(REPLAY_BUFFER_SIZE), next_id: 1 }
+    }
+}
+
+impl Actor for Broadcaster {
+    type Context = Context<Self>;
+}
+
+impl Handler<Subscribe> for Broadcaster {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _ctx: &mut Context<Self>) {
+        self.subscribers.push(Subscriber::Actor(msg.0));
+    }
+}
+
+impl Handler<SubscribeStream> for Broadcaster {
+    type Result = Result<UnboundedReceiver<Published>, ()>;
+
+    fn handle(&mut self, msg: SubscribeStream, _ctx: &mut Context<Self>) -> Self::Result {
+        let (sender, receiver) = unbounded();
+        if let Some(last_event_id) = msg.last_event_id {
+            self.history.iter()
+                .filter(|published| published.id > last_event_id)
+                .for_each(|published| { let _ = sender.unbounded_send(published.clone()); });
+        }
+        self.subscribers.push(Subscriber::Stream(sender));
+        Ok(receiver)
+    }
+}
+
+impl Handler<Publish> for Broadcaster {
+    type Result = ();
+
+    fn handle(&mut self, msg: Publish, _ctx: &mut Context<Self>) {
+        let published = Published { id: self.next_id, event: msg.0 };
+        self.next_id += 1;
+
+        if self.history.len() == REPLAY_BUFFER_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back(published.clone());
+
+        // Disconnected clients are dropped on the first failed delivery
+        self.subscribers.retain(|subscriber| subscriber.send(published.clone()));
+    }
+}
+
+pub fn start_broadcaster() -> Addr<Broadcaster> {
+    Broadcaster::default().start()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::{System, Arbiter};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::timer::Delay;
+    use tokio::prelude::{Future, Stream};
+
+    struct Collector(Arc<Mutex<Vec<Published>>>);
+
+    impl Actor for Collector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Published> for Collector {
+        type Result = ();
+
+        fn handle(&mut self, msg: Published, _ctx: &mut Context<Self>) {
+            self.0.lock().unwrap().push(msg);
+        }
+    }
+
+    fn stop_system_later() {
+        Arbiter::spawn(Delay::new(Instant::now() + Duration::from_millis(50))
+            .then(|_| {
+                System::current().stop();
+                Ok(())
+            }));
+    }
+
+    #[test]
+    fn test_serialize_event() {
+        let event = LiveEvent::WinnerRevealed { position: 1, winner: Profile { first_name: "Francois".to_string(), last_name: "Teychene".to_string() } };
+        assert_eq!(::serde_json::to_string(&event).unwrap(), r#"{"type":"winner_revealed","position":1,"winner":{"first_name":"Francois","last_name":"Teychene"}}"#);
+        assert_eq!(::serde_json::to_string(&LiveEvent::Started { nb: 3, prize: None }).unwrap(), r#"{"type":"started","nb":3}"#);
+        assert_eq!(::serde_json::to_string(&LiveEvent::Started { nb: 1, prize: Some("IntelliJ licence".to_string()) }).unwrap(), r#"{"type":"started","nb":1,"prize":"IntelliJ licence"}"#);
+        assert_eq!(::serde_json::to_string(&LiveEvent::AttendeesUpdated { count: 42 }).unwrap(), r#"{"type":"attendees_updated","count":42}"#);
+        assert_eq!(LiveEvent::AttendeesUpdated { count: 42 }.name(), "attendees_updated");
+    }
+
+    #[test]
+    fn test_publish() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let collected = received.clone();
+
+        System::run(move || {
+            let broadcaster = start_broadcaster();
+            let first = Collector(collected.clone()).start();
+            let second = Collector(collected).start();
+            broadcaster.do_send(Subscribe(first.recipient()));
+            broadcaster.do_send(Subscribe(second.recipient()));
+            broadcaster.do_send(Publish(LiveEvent::Started { nb: 2, prize: None }));
+            stop_system_later();
+        });
+
+        let expected = Published { id: 1, event: LiveEvent::Started { nb: 2, prize: None } };
+        assert_eq!(*received.lock().unwrap(), vec![expected.clone(), expected]);
+    }
+
+    #[test]
+    fn test_subscribe_stream_replay() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let collected = received.clone();
+
+        System::run(move || {
+            let broadcaster = start_broadcaster();
+            (0..REPLAY_BUFFER_SIZE + 5).for_each(|nb| broadcaster.do_send(Publish(LiveEvent::Started { nb, prize: None })));
+            let replay = broadcaster.send(SubscribeStream { last_event_id: Some(REPLAY_BUFFER_SIZE as u64 + 2) });
+            broadcaster.do_send(Publish(LiveEvent::AttendeesUpdated { count: 1 }));
+            // Older than the buffer : everything still kept is replayed
+            let old = broadcaster.send(SubscribeStream { last_event_id: Some(1) });
+
+            Arbiter::spawn(replay.join(old)
+                .map(move |(replay, old)| {
+                    let replay = replay.unwrap();
+                    let old = old.unwrap();
+                    Arbiter::spawn(replay.take(4).collect()
+                        .join(old.take(REPLAY_BUFFER_SIZE as u64).collect())
+                        .map(move |(replay, old)| {
+                            collected.lock().unwrap().push((replay, old.len(), old.first().map(|published: &Published| published.id)));
+                            System::current().stop();
+                        }));
+                })
+                .map_err(|_| ()));
+        });
+
+        let received = received.lock().unwrap();
+        let (ref replay, old_count, old_first) = received[0];
+        assert_eq!(replay.iter().map(|published| published.id).collect::<Vec<_>>(), vec![103, 104, 105, 106]);
+        assert_eq!(replay[3].event, LiveEvent::AttendeesUpdated { count: 1 });
+        assert_eq!(old_count, REPLAY_BUFFER_SIZE);
+        assert_eq!(old_first, Some(7));
+    }
+}
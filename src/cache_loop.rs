@@ -1,24 +1,174 @@
 use actix::prelude::Addr;
-use tokio::timer::Interval;
-use std::time::Instant;
-use tokio::prelude::future::Future;
-use tokio::prelude::Stream;
+use std::cmp::{max, min};
+use tokio::prelude::future::{self, Either, Future, Loop};
 use core::time::Duration;
+use chrono::{self, DateTime, Utc};
 
-use lotterycache::{LotteryCache, UpdateAttendees, UpdateAttendeesResponse};
-
-
-pub fn cache_update_interval(duration: u64, addr: Addr<LotteryCache>, token: String, organizer: String) -> impl Future<Item=(), Error=()> + 'static {
-    Interval::new(Instant::now(), Duration::from_secs(duration))
-        .then(move |_instant| addr.send(UpdateAttendees { token: token.clone(), organizer: organizer.clone() })
-            .map_err(|err| error!("Error on sending update message : {:?}", err)))
-        .for_each(move |res| {
-            match res {
-                UpdateAttendeesResponse::Updated => info!("Attendees cache updated"),
-                UpdateAttendeesResponse::NoEventAvailable => info!("No event available on eventbrite"),
-                UpdateAttendeesResponse::EventbriteError { error: ref e } => info!("Error on eventbrite : {}", e),
-                UpdateAttendeesResponse::UnexpectedError { error: ref e } => error!("Unexpected error on update attendees \n{:?}", e)
-            };
-            Ok(())
-        })
-}
\ No newline at end of file
+use config::SharedConfig;
+use eventbrite::Event;
+use lotterycache::{GetEvent, LotteryCache, ScheduleRefresh, UpdateAttendees, UpdateAttendeesResponse};
+use shutdown::ShutdownFlag;
+
+
+/// Delays between the refreshes of a tenant
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    /// From `active_before` the start of the event until its end
+    pub active: Duration,
+    /// Far from the event, or without event
+    pub idle: Duration,
+    pub active_before: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Schedule {
+    /// Delay around the event : frequent during the event and before its start, without sleeping past the start of the frequent refreshes
+    fn base_delay(&self, event: Option<&Event>, now: DateTime<Utc>) -> Duration {
+        let (start, end) = match event.and_then(|event| event.start.map(|start| (start, event.end.unwrap_or(start)))) {
+            Some(timing) => timing,
+            None => return self.idle,
+        };
+        let active_from = start - chrono::Duration::from_std(self.active_before).unwrap_or_else(|_| chrono::Duration::zero());
+        if now < active_from {
+            let until_active = (active_from - now).to_std().unwrap_or(self.active);
+            max(self.active, min(self.idle, until_active))
+        } else if now <= end {
+            self.active
+        } else {
+            self.idle
+        }
+    }
+
+    /// Delay before the next refresh, slowed down exponentially up to `max_backoff` after `failures` consecutive failures
+    pub fn next_delay(&self, event: Option<&Event>, now: DateTime<Utc>, failures: u32) -> Duration {
+        if failures == 0 {
+            return self.base_delay(event, now);
+        }
+        let backoff = min(self.active * 2u32.pow(min(failures, 16)), self.max_backoff);
+        match event {
+            Some(_) => max(backoff, self.base_delay(event, now)),
+            None => backoff,
+        }
+    }
+}
+
+fn log_response(tenant: &str, response: &UpdateAttendeesResponse) {
+    match *response {
+        UpdateAttendeesResponse::Updated => info!("[{}] Attendees cache updated", tenant),
+        UpdateAttendeesResponse::NoEventAvailable => info!("[{}] No event available on eventbrite", tenant),
+        UpdateAttendeesResponse::EventbriteError { error: ref e } => info!("[{}] Error on eventbrite : {}", tenant, e),
+        UpdateAttendeesResponse::UnexpectedError { error: ref e } => error!("[{}] Unexpected error on update attendees \n{:?}", tenant, e)
+    };
+}
+
+/// Refresh the attendees of a tenant until the shutdown, each refresh is planned with `schedule` from the current event.
+/// The credentials are read from the shared configuration on each refresh, so a reload applies on the next one.
+/// Errors are logged and count as failed refreshes, slowing the next ones down : only the shutdown ends the loop
+pub fn cache_update_loop(schedule: Schedule, addr: Addr<LotteryCache>, tenant: String, config: SharedConfig, shutdown: ShutdownFlag) -> impl Future<Item=(), Error=()> + 'static {
+    future::loop_fn(0, move |failures: u32| {
+        if shutdown.is_set() {
+            info!("[{}] Refreshes stopped", tenant);
+            return Either::A(future::ok(Loop::Break(())));
+        }
+        let (cache, scheduler, schedule, shutdown) = (addr.clone(), addr.clone(), schedule.clone(), shutdown.clone());
+        let (name, logged_name, scheduled_name) = (tenant.clone(), tenant.clone(), tenant.clone());
+        let refreshed = future::result(config.tenant(&tenant).ok_or_else(|| error!("[{}] No credentials for the refresh", name)))
+            .and_then({
+                let addr = addr.clone();
+                move |credentials| addr.send(UpdateAttendees { token: credentials.token, organizer: credentials.organizer })
+                    .map_err(|err| error!("Error on sending update message : {:?}", err))
+            })
+            .and_then(move |response| {
+                log_response(&logged_name, &response);
+                let failures = match response {
+                    UpdateAttendeesResponse::Updated | UpdateAttendeesResponse::NoEventAvailable => 0,
+                    _ => failures + 1,
+                };
+                cache.send(GetEvent {})
+                    .map(move |event| (failures, event.ok()))
+                    .map_err(|err| error!("Error on sending event message : {:?}", err))
+            })
+            .or_else(move |_| Ok((failures + 1, None)))
+            .and_then(move |(failures, event)| {
+                let now = Utc::now();
+                let delay = schedule.next_delay(event.as_ref(), now, failures);
+                debug!("[{}] Next refresh in {}s", scheduled_name, delay.as_secs());
+                scheduler.do_send(ScheduleRefresh { at: now + chrono::Duration::from_std(delay).unwrap_or_else(|_| chrono::Duration::zero()) });
+                shutdown.sleep(delay)
+                    .then(move |slept| match slept {
+                        Ok(_) => Ok(Loop::Continue(failures)),
+                        // The timer is only shut down with the system
+                        Err(ref err) if err.is_shutdown() => Ok(Loop::Break(())),
+                        Err(err) => {
+                            error!("Error on the refresh timer : {:?}", err);
+                            Ok(Loop::Continue(failures + 1))
+                        }
+                    })
+            });
+        Either::B(refreshed)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::{Actor, System};
+    use broadcast::start_broadcaster;
+    use chrono::TimeZone;
+    use config::Config;
+    use lotterycache::GetCacheStatus;
+    use metrics::Metrics;
+    use std::time::Instant;
+    use tokio::timer::Delay;
+
+    fn schedule() -> Schedule {
+        Schedule { active: Duration::from_secs(10), idle: Duration::from_secs(300), active_before: Duration::from_secs(3600), max_backoff: Duration::from_secs(600) }
+    }
+
+    #[test]
+    fn test_next_delay() {
+        let event = Event {
+            id: "52097259305".to_string(),
+            start: Some(Utc.with_ymd_and_hms(2026, 10, 20, 17, 0, 0).unwrap()),
+            end: Some(Utc.with_ymd_and_hms(2026, 10, 20, 20, 0, 0).unwrap()),
+        };
+        let at = |hour: u32, minute: u32| Utc.with_ymd_and_hms(2026, 10, 20, hour, minute, 0).unwrap();
+        let schedule = schedule();
+
+        assert_eq!(schedule.next_delay(Some(&event), at(10, 0), 0), Duration::from_secs(300));
+        assert_eq!(schedule.next_delay(Some(&event), at(15, 58), 0), Duration::from_secs(120));
+        assert_eq!(schedule.next_delay(Some(&event), at(16, 30), 0), Duration::from_secs(10));
+        assert_eq!(schedule.next_delay(Some(&event), at(19, 59), 0), Duration::from_secs(10));
+        assert_eq!(schedule.next_delay(Some(&event), at(21, 0), 0), Duration::from_secs(300));
+        assert_eq!(schedule.next_delay(None, at(16, 30), 0), Duration::from_secs(300));
+        let without_timing = Event { id: "51124390428".to_string(), start: None, end: None };
+        assert_eq!(schedule.next_delay(Some(&without_timing), at(16, 30), 0), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn test_next_delay_after_failures() {
+        let event = Event { id: "52097259305".to_string(), start: Some(Utc.with_ymd_and_hms(2026, 10, 20, 17, 0, 0).unwrap()), end: None };
+        let during = Utc.with_ymd_and_hms(2026, 10, 20, 16, 30, 0).unwrap();
+        let schedule = schedule();
+
+        assert_eq!(schedule.next_delay(Some(&event), during, 1), Duration::from_secs(20));
+        assert_eq!(schedule.next_delay(Some(&event), during, 3), Duration::from_secs(80));
+        assert_eq!(schedule.next_delay(Some(&event), during, 10), Duration::from_secs(600));
+        assert_eq!(schedule.next_delay(Some(&event), during, 100), Duration::from_secs(600));
+        assert_eq!(schedule.next_delay(None, during, 1), Duration::from_secs(20));
+        assert_eq!(schedule.next_delay(Some(&event), Utc.with_ymd_and_hms(2026, 10, 21, 10, 0, 0).unwrap(), 1), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn test_cache_update_loop_goes_on_after_errors() {
+        let mut system = System::new("test");
+        let cache = system.block_on(future::lazy(|| Ok::<_, ()>(LotteryCache::new("bordeaux".to_string(), start_broadcaster(), Metrics::new()).start()))).unwrap();
+        let schedule = Schedule { active: Duration::from_millis(10), idle: Duration::from_secs(1), active_before: Duration::from_secs(3600), max_backoff: Duration::from_millis(40) };
+
+        // Without credentials every refresh fails, the loop still runs when the delay ends
+        let looping = cache_update_loop(schedule, cache.clone(), "bordeaux".to_string(), SharedConfig::new(Config::default()), ShutdownFlag::default());
+        let raced = system.block_on(looping.select2(Delay::new(Instant::now() + Duration::from_millis(200))));
+        assert!(matches!(raced, Ok(Either::B(_))));
+        assert!(system.block_on(cache.send(GetCacheStatus)).unwrap().next_refresh.is_some());
+    }
+}
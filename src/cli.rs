@@ -0,0 +1,212 @@
+//! Commands of the binary run without the http server, on the configured sources and database
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use config::Config;
+use database::{self, DbConnection};
+use eventbrite::{get_current_event, list_events, load_attendees, Profile};
+use failure::Error;
+use lottery::{draw, DrawRequest};
+use repository::{self, NewDraw, Winner};
+use serde_json;
+use chrono::Utc;
+use tenant::DEFAULT_TENANT;
+use uuid;
+
+fn tenant_arg() -> Arg<'static, 'static> {
+    Arg::with_name("tenant")
+        .long("tenant")
+        .value_name("NAME")
+        .default_value(DEFAULT_TENANT)
+        .help("Tenant of the command")
+}
+
+/// Arguments of the binary, the http server is started without subcommand
+pub fn app() -> App<'static, 'static> {
+    App::new("lottery-presentation")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Draw winners among the attendees of eventbrite events")
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(Arg::with_name("config")
+            .long("config")
+            .value_name("FILE")
+            .env("LOTTERY_CONFIG")
+            .global(true)
+            .help("TOML configuration file, overridden by the environment variables"))
+        .arg(Arg::with_name("check-config")
+            .long("check-config")
+            .help("Validate the configuration and exit"))
+        .subcommand(SubCommand::with_name("serve")
+            .about("Start the http server, the default command"))
+        .subcommand(SubCommand::with_name("draw")
+            .about("Draw winners among the attendees of the current event, nothing is recorded")
+            .arg(tenant_arg())
+            .arg(Arg::with_name("nb").long("nb").value_name("NB").default_value("1").help("Number of winners"))
+            .arg(Arg::with_name("exclude").long("exclude").value_name("NAMES").help("Full names of the attendees who can't win, separated by commas"))
+            .arg(Arg::with_name("seed").long("seed").value_name("SEED").help("Seed of the draw, to replay it")))
+        .subcommand(SubCommand::with_name("events")
+            .about("List the live events of the tenant, the current one first")
+            .arg(tenant_arg()))
+        .subcommand(SubCommand::with_name("winners")
+            .about("List or export the recorded winners")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("list")
+                .arg(tenant_arg())
+                .arg(Arg::with_name("event").long("event").value_name("ID").help("Only the winners of this event")))
+            .subcommand(SubCommand::with_name("export")
+                .arg(tenant_arg())
+                .arg(Arg::with_name("event").long("event").value_name("ID").help("Only the winners of this event"))
+                .arg(Arg::with_name("format").long("format").possible_values(&["csv", "json"]).default_value("csv"))))
+        .subcommand(SubCommand::with_name("migrate")
+            .about("Apply the pending database migrations"))
+        .subcommand(SubCommand::with_name("seed")
+            .about("Insert a draw and its recorded winners, to try the api on test data")
+            .arg(tenant_arg())
+            .arg(Arg::with_name("event").long("event").value_name("ID").default_value("seed").help("Event of the draw"))
+            .arg(Arg::with_name("count").long("count").value_name("COUNT").default_value("10").help("Number of winners")))
+}
+
+fn parse<T: ::std::str::FromStr>(args: &ArgMatches, name: &str) -> Result<Option<T>, Error> {
+    args.value_of(name)
+        .map(|value| value.parse().map_err(|_| format_err!("Invalid --{} : {}", name, value)))
+        .map_or(Ok(None), |value| value.map(Some))
+}
+
+fn connection(config: &Config) -> Result<DbConnection, Error> {
+    if config.database.url.trim().is_empty() {
+        bail!("database.url (DATABASE_URL) is mandatory");
+    }
+    database::migrate(&config.database.url)
+}
+
+fn draw_command(config: &Config, args: &ArgMatches) -> Result<(), Error> {
+    let tenant = config.tenant(args.value_of("tenant").unwrap_or(DEFAULT_TENANT))?;
+    let request = DrawRequest {
+        nb: parse(args, "nb")?.unwrap_or(1),
+        excluded: args.value_of("exclude").map_or_else(Vec::new, |names| names.split(',')
+            .map(|name| name.trim().to_owned())
+            .filter(|name| !name.is_empty())
+            .collect()),
+        seed: parse(args, "seed")?,
+        prize: None,
+    };
+    let event = get_current_event(&tenant.organizer, &tenant.token)?;
+    let attendees: Vec<Profile> = load_attendees(&event.id, &tenant.token)?.into_iter().map(|attendee| attendee.profile).collect();
+    println!("Event {}, {} attendees", event.id, attendees.len());
+    draw(&request, &attendees)?.iter().enumerate()
+        .for_each(|(index, winner)| println!("{}. {} {}", index + 1, winner.first_name, winner.last_name));
+    Ok(())
+}
+
+fn events_command(config: &Config, args: &ArgMatches) -> Result<(), Error> {
+    let tenant = config.tenant(args.value_of("tenant").unwrap_or(DEFAULT_TENANT))?;
+    list_events(&tenant.organizer, &tenant.token)?.iter().for_each(|event| println!("{}", event.id));
+    Ok(())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+fn to_csv(winners: &[Winner]) -> String {
+    winners.iter()
+        .map(|winner| [&winner.id, &winner.first_name, &winner.last_name, &winner.event_id].iter()
+            .map(|value| csv_field(value))
+            .collect::<Vec<_>>()
+            .join(","))
+        .fold("id,first_name,last_name,event_id\n".to_owned(), |csv, line| csv + &line + "\n")
+}
+
+fn winners_command(config: &Config, args: &ArgMatches) -> Result<(), Error> {
+    let (command, args) = match args.subcommand() {
+        (command, Some(args)) => (command, args),
+        _ => bail!("A winners command is mandatory : list or export"),
+    };
+    let conn = connection(config)?;
+    let winners = repository::list_winners(&conn, args.value_of("tenant").unwrap_or(DEFAULT_TENANT), args.value_of("event"))?;
+    match (command, args.value_of("format")) {
+        ("export", Some("json")) => println!("{}", serde_json::to_string_pretty(&winners)?),
+        ("export", _) => print!("{}", to_csv(&winners)),
+        _ => winners.iter().for_each(|winner| println!("{}  {} {}  (event {})", winner.id, winner.first_name, winner.last_name, winner.event_id)),
+    }
+    Ok(())
+}
+
+const SEED_FIRST_NAMES: [&str; 5] = ["Ada", "Grace", "Alan", "Linus", "Margaret"];
+const SEED_LAST_NAMES: [&str; 5] = ["Lovelace", "Hopper", "Turing", "Torvalds", "Hamilton"];
+
+fn seed_profiles(count: usize) -> Vec<Profile> {
+    (0..count)
+        .map(|index| Profile {
+            first_name: SEED_FIRST_NAMES[index % SEED_FIRST_NAMES.len()].to_owned(),
+            last_name: format!("{}{}", SEED_LAST_NAMES[(index / SEED_FIRST_NAMES.len()) % SEED_LAST_NAMES.len()],
+                               if index < SEED_FIRST_NAMES.len() * SEED_LAST_NAMES.len() { String::new() } else { format!(" {}", index) }),
+        })
+        .collect()
+}
+
+fn seed_command(config: &Config, args: &ArgMatches) -> Result<(), Error> {
+    let conn = connection(config)?;
+    let tenant = args.value_of("tenant").unwrap_or(DEFAULT_TENANT);
+    let draw_id = format!("{}", uuid::Uuid::new_v4());
+    let draw = repository::insert_draw(&conn, &NewDraw {
+        id: &draw_id,
+        event_id: args.value_of("event").unwrap_or("seed"),
+        author: "seed",
+        seed: None,
+        prize: None,
+        drawn_at: Utc::now().naive_utc(),
+        tenant,
+    }, &seed_profiles(parse(args, "count")?.unwrap_or(10)))?;
+    for result in &draw.results {
+        repository::record_draw_result(&conn, tenant, &result.id)?;
+    }
+    println!("Draw {} inserted with {} recorded winners for the tenant {}", draw.id, draw.results.len(), tenant);
+    Ok(())
+}
+
+/// Run a subcommand other than `serve`
+pub fn run(command: &str, args: &ArgMatches, config: &Config) -> Result<(), Error> {
+    match command {
+        "draw" => draw_command(config, args),
+        "events" => events_command(config, args),
+        "winners" => winners_command(config, args),
+        "migrate" => connection(config).map(|_| println!("Database schema up to date")),
+        "seed" => seed_command(config, args),
+        other => bail!("Unknown command {}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_csv() {
+        let winners = vec![
+            Winner { id: "1".to_string(), first_name: "Francois".to_string(), last_name: "Teychene".to_string(), event_id: "51124390428".to_string() },
+            Winner { id: "2".to_string(), first_name: "Jean, \"JB\"".to_string(), last_name: "Bernard".to_string(), event_id: "51124390428".to_string() },
+        ];
+        assert_eq!(to_csv(&winners), "id,first_name,last_name,event_id\n1,Francois,Teychene,51124390428\n2,\"Jean, \"\"JB\"\"\",Bernard,51124390428\n");
+    }
+
+    #[test]
+    fn test_seed_profiles() {
+        let profiles = seed_profiles(27);
+        assert_eq!(profiles[0], Profile { first_name: "Ada".to_string(), last_name: "Lovelace".to_string() });
+        assert_eq!(profiles[6], Profile { first_name: "Grace".to_string(), last_name: "Hopper".to_string() });
+        assert_eq!(profiles[26], Profile { first_name: "Grace".to_string(), last_name: "Lovelace 26".to_string() });
+    }
+
+    #[test]
+    fn test_app() {
+        let matches = app().get_matches_from_safe(vec!["lottery-presentation", "winners", "export", "--tenant", "bordeaux", "--format", "json"]).unwrap();
+        let (_, winners) = matches.subcommand();
+        let (command, export) = winners.unwrap().subcommand();
+        assert_eq!(command, "export");
+        assert_eq!(export.unwrap().value_of("tenant"), Some("bordeaux"));
+        assert!(app().get_matches_from_safe(vec!["lottery-presentation", "winners", "export", "--format", "xml"]).is_err());
+    }
+}
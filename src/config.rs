@@ -0,0 +1,405 @@
+//! Configuration of the server : a TOML file, overridden by the environment variables, validated at startup
+use auth::ApiKeys;
+use cache_loop::Schedule;
+use database::DatabaseConfig;
+use schemars::JsonSchema;
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tenant::{self, TenantConfig};
+use toml;
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum ConfigError {
+    #[fail(display = "Unable to read the configuration file {} : {}", path, cause)]
+    Unreadable { path: String, cause: String },
+    #[fail(display = "Invalid configuration file {} : {}", path, cause)]
+    Malformed { path: String, cause: String },
+    #[fail(display = "Invalid configuration :\n - {}", _0)]
+    Invalid(String),
+}
+
+fn invalid(problems: &[String]) -> ConfigError {
+    ConfigError::Invalid(problems.join("\n - "))
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub bind: String,
+    pub port: u16,
+    /// Origins allowed for CORS requests, any origin when empty
+    pub cors_allowed_origins: Vec<String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> HttpConfig {
+        HttpConfig { bind: "0.0.0.0".to_owned(), port: 8088, cors_allowed_origins: vec![] }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseSection {
+    pub url: String,
+    pub pool_size: u32,
+    /// Seconds to wait for a free connection
+    pub pool_timeout: u64,
+    pub executors: usize,
+}
+
+impl Default for DatabaseSection {
+    fn default() -> DatabaseSection {
+        DatabaseSection { url: String::new(), pool_size: 10, pool_timeout: 5, executors: 3 }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RefreshConfig {
+    /// Seconds between two refreshes of the attendees from eventbrite around the current event
+    pub interval: u64,
+    /// Seconds between two refreshes far from the event, or without event
+    pub idle_interval: u64,
+    /// Seconds before the start of the event from which the refreshes are frequent, until its end
+    pub active_before: u64,
+    /// Maximum seconds between two refreshes after consecutive failures
+    pub max_backoff: u64,
+    /// Seconds since the last successful refresh after which the server is not ready anymore
+    pub cache_max_age: u64,
+}
+
+impl Default for RefreshConfig {
+    fn default() -> RefreshConfig {
+        RefreshConfig { interval: 10, idle_interval: 300, active_before: 3600, max_backoff: 600, cache_max_age: 60 }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Maximum number of winners of a draw
+    pub max_draw: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> LimitsConfig {
+        LimitsConfig { max_draw: 50 }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Seconds given to the pending requests and database writes on SIGINT/SIGTERM before the process exits anyway
+    pub timeout: u16,
+}
+
+/// Below the 10s grace period of `docker stop`, the drain ends before the container is killed
+impl Default for ShutdownConfig {
+    fn default() -> ShutdownConfig {
+        ShutdownConfig { timeout: 8 }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Admin tokens, as `name:token`
+    pub admin_tokens: Vec<String>,
+}
+
+impl AuthConfig {
+    pub fn api_keys(&self) -> Result<ApiKeys, String> {
+        ApiKeys::parse(&self.admin_tokens.join(",")).map_err(|error| error.to_string())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub http: HttpConfig,
+    pub database: DatabaseSection,
+    pub refresh: RefreshConfig,
+    pub limits: LimitsConfig,
+    pub auth: AuthConfig,
+    pub shutdown: ShutdownConfig,
+    /// Sources of the attendees, one per tenant
+    pub tenants: Vec<TenantConfig>,
+}
+
+/// Changes applied by a reload, the other sections are only read at startup
+#[derive(Serialize, JsonSchema, Debug, PartialEq)]
+pub struct Reloaded {
+    /// Tenants with new eventbrite credentials, used from their next refresh
+    pub updated_tenants: Vec<String>,
+    /// Sections which changed but are not applied until the next restart
+    pub restart_required: Vec<String>,
+}
+
+/// Value of an environment variable, `problems` collects the values which can't be parsed
+fn parsed_var<T: FromStr, F: Fn(&str) -> Option<String>>(var: &F, name: &str, problems: &mut Vec<String>) -> Option<T> {
+    var(name).and_then(|value| value.trim().parse().map_err(|_| problems.push(format!("{} has an invalid value : {}", name, value))).ok())
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(|item| item.trim().to_owned())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn check<T: Display>(problems: &mut Vec<String>, valid: bool, problem: T) {
+    if !valid {
+        problems.push(problem.to_string());
+    }
+}
+
+impl Config {
+    /// Parse a configuration file, the missing values get their default
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let content = fs::read_to_string(path)
+            .map_err(|error| ConfigError::Unreadable { path: path.display().to_string(), cause: error.to_string() })?;
+        toml::from_str(&content)
+            .map_err(|error| ConfigError::Malformed { path: path.display().to_string(), cause: error.to_string() })
+    }
+
+    /// Override the values set by the environment variables, read with `var`.
+    /// A tenant configured by the environment replaces the one of the file with the same name
+    pub fn apply_env<F: Fn(&str) -> Option<String>>(&mut self, var: F) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        if let Some(bind) = var("HTTP_BIND") { self.http.bind = bind; }
+        if let Some(port) = parsed_var(&var, "HTTP_PORT", &mut problems) { self.http.port = port; }
+        if let Some(origins) = var("CORS_ALLOWED_ORIGINS") { self.http.cors_allowed_origins = split_list(&origins); }
+        if let Some(url) = var("DATABASE_URL") { self.database.url = url; }
+        if let Some(pool_size) = parsed_var(&var, "DATABASE_POOL_SIZE", &mut problems) { self.database.pool_size = pool_size; }
+        if let Some(pool_timeout) = parsed_var(&var, "DATABASE_POOL_TIMEOUT", &mut problems) { self.database.pool_timeout = pool_timeout; }
+        if let Some(executors) = parsed_var(&var, "DATABASE_EXECUTORS", &mut problems) { self.database.executors = executors; }
+        if let Some(interval) = parsed_var(&var, "REFRESH_INTERVAL", &mut problems) { self.refresh.interval = interval; }
+        if let Some(idle_interval) = parsed_var(&var, "REFRESH_IDLE_INTERVAL", &mut problems) { self.refresh.idle_interval = idle_interval; }
+        if let Some(active_before) = parsed_var(&var, "REFRESH_ACTIVE_BEFORE", &mut problems) { self.refresh.active_before = active_before; }
+        if let Some(max_backoff) = parsed_var(&var, "REFRESH_MAX_BACKOFF", &mut problems) { self.refresh.max_backoff = max_backoff; }
+        if let Some(cache_max_age) = parsed_var(&var, "CACHE_MAX_AGE", &mut problems) { self.refresh.cache_max_age = cache_max_age; }
+        if let Some(max_draw) = parsed_var(&var, "MAX_DRAW_COUNT", &mut problems) { self.limits.max_draw = max_draw; }
+        if let Some(timeout) = parsed_var(&var, "SHUTDOWN_TIMEOUT", &mut problems) { self.shutdown.timeout = timeout; }
+        if let Some(admin_tokens) = var("ADMIN_TOKENS") { self.auth.admin_tokens = split_list(&admin_tokens); }
+        match tenant::configs_from_env(&var) {
+            Ok(configs) => configs.into_iter().for_each(|config| {
+                self.tenants.retain(|tenant| tenant.name != config.name);
+                self.tenants.push(config);
+            }),
+            Err(error) => problems.push(error.to_string()),
+        }
+        if problems.is_empty() { Ok(()) } else { Err(invalid(&problems)) }
+    }
+
+    /// Every problem of the configuration, so they can be fixed at once
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        check(&mut problems, !self.http.bind.trim().is_empty(), "http.bind (HTTP_BIND) can't be empty");
+        check(&mut problems, !self.database.url.trim().is_empty(), "database.url (DATABASE_URL) is mandatory");
+        check(&mut problems, self.database.pool_size > 0, "database.pool_size (DATABASE_POOL_SIZE) must be at least 1");
+        check(&mut problems, self.database.executors > 0, "database.executors (DATABASE_EXECUTORS) must be at least 1");
+        check(&mut problems, self.refresh.interval > 0, "refresh.interval (REFRESH_INTERVAL) must be at least 1 second");
+        check(&mut problems, self.refresh.cache_max_age > self.refresh.interval,
+              format!("refresh.cache_max_age (CACHE_MAX_AGE) must be greater than the refresh interval ({}s), the server would never be ready", self.refresh.interval));
+        check(&mut problems, self.refresh.idle_interval >= self.refresh.interval,
+              format!("refresh.idle_interval (REFRESH_IDLE_INTERVAL) can't be shorter than the refresh interval ({}s)", self.refresh.interval));
+        check(&mut problems, self.refresh.max_backoff >= self.refresh.interval,
+              format!("refresh.max_backoff (REFRESH_MAX_BACKOFF) can't be shorter than the refresh interval ({}s)", self.refresh.interval));
+        check(&mut problems, self.limits.max_draw > 0, "limits.max_draw (MAX_DRAW_COUNT) must be at least 1");
+        check(&mut problems, self.shutdown.timeout > 0, "shutdown.timeout (SHUTDOWN_TIMEOUT) must be at least 1 second");
+        if let Err(error) = self.auth.api_keys() {
+            problems.push(format!("auth.admin_tokens (ADMIN_TOKENS) : {}", error));
+        }
+        if let Err(error) = tenant::check_configs(&self.tenants) {
+            problems.push(error.to_string());
+        }
+        if problems.is_empty() { Ok(()) } else { Err(invalid(&problems)) }
+    }
+
+    /// Configuration of the file, when given, overridden by the environment. The commands check the values they need
+    pub fn read<F: Fn(&str) -> Option<String>>(path: Option<&Path>, var: F) -> Result<Config, ConfigError> {
+        let mut config = match path {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        config.apply_env(var)?;
+        Ok(config)
+    }
+
+    /// Configuration of the server, read then validated
+    pub fn load<F: Fn(&str) -> Option<String>>(path: Option<&Path>, var: F) -> Result<Config, ConfigError> {
+        let config = Config::read(path, var)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn tenant(&self, name: &str) -> Result<&TenantConfig, ConfigError> {
+        self.tenants.iter()
+            .find(|tenant| tenant.name == name)
+            .ok_or_else(|| invalid(&[format!("No tenant {} configured", name)]))
+    }
+
+    /// Changes from this configuration to `new`, the tenants can't be added or removed without restart
+    pub fn reloaded(&self, new: &Config) -> Result<Reloaded, ConfigError> {
+        let names = |config: &Config| config.tenants.iter().map(|tenant| tenant.name.clone()).collect::<BTreeSet<String>>();
+        if names(self) != names(new) {
+            return Err(invalid(&["Tenants can't be added or removed without restart".to_owned()]));
+        }
+        let sections = [
+            ("http", self.http != new.http),
+            ("database", self.database != new.database),
+            ("refresh", self.refresh != new.refresh),
+            ("limits", self.limits != new.limits),
+            ("auth", self.auth != new.auth),
+            ("shutdown", self.shutdown != new.shutdown),
+        ];
+        Ok(Reloaded {
+            updated_tenants: new.tenants.iter()
+                .filter(|tenant| !self.tenants.contains(tenant))
+                .map(|tenant| tenant.name.clone())
+                .collect(),
+            restart_required: sections.iter()
+                .filter(|(_, changed)| *changed)
+                .map(|(section, _)| section.to_string())
+                .collect(),
+        })
+    }
+
+    pub fn refresh_schedule(&self) -> Schedule {
+        Schedule {
+            active: Duration::from_secs(self.refresh.interval),
+            idle: Duration::from_secs(self.refresh.idle_interval),
+            active_before: Duration::from_secs(self.refresh.active_before),
+            max_backoff: Duration::from_secs(self.refresh.max_backoff),
+        }
+    }
+
+    pub fn database_config(&self) -> DatabaseConfig {
+        DatabaseConfig {
+            url: self.database.url.clone(),
+            pool_size: self.database.pool_size,
+            connection_timeout: Duration::from_secs(self.database.pool_timeout),
+            executors: self.database.executors,
+        }
+    }
+}
+
+/// Configuration read by the running server, the tenants credentials are replaced on reload
+#[derive(Clone)]
+pub struct SharedConfig(Arc<RwLock<Config>>);
+
+impl SharedConfig {
+    pub fn new(config: Config) -> SharedConfig {
+        SharedConfig(Arc::new(RwLock::new(config)))
+    }
+
+    /// Current credentials of a tenant
+    pub fn tenant(&self, name: &str) -> Option<TenantConfig> {
+        self.0.read().unwrap().tenants.iter().find(|tenant| tenant.name == name).cloned()
+    }
+
+    /// Apply the tenants of a validated configuration, the other sections are kept until the next restart
+    pub fn reload(&self, config: Config) -> Result<Reloaded, ConfigError> {
+        let mut current = self.0.write().unwrap();
+        let reloaded = current.reloaded(&config)?;
+        current.tenants = config.tenants;
+        Ok(reloaded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::env;
+
+    const FILE: &str = r#"
+        [http]
+        port = 9000
+
+        [database]
+        url = "lottery.db"
+
+        [refresh]
+        interval = 30
+        cache_max_age = 120
+
+        [auth]
+        admin_tokens = ["francois:5O5ICDI5I4LUFCAZRSTX"]
+
+        [[tenants]]
+        name = "bordeaux"
+        organizer = "1464915124"
+        token = "5O5ICDI5"
+    "#;
+
+    #[test]
+    fn test_file_with_env_overrides() {
+        let mut config: Config = toml::from_str(FILE).unwrap();
+        assert_eq!((config.http.bind.as_ref(), config.http.port), ("0.0.0.0", 9000));
+        assert_eq!(config.limits.max_draw, 50);
+        assert_eq!(config.shutdown.timeout, 8);
+        assert!(config.shutdown.timeout < 10, "the default shutdown timeout must end before docker stop kills the container");
+
+        config.apply_env(env(&[
+            ("HTTP_PORT", "8088"), ("MAX_DRAW_COUNT", "10"), ("SHUTDOWN_TIMEOUT", "5"), ("CORS_ALLOWED_ORIGINS", "https://jug-montpellier.org, "),
+            ("TENANTS", "bordeaux"), ("BORDEAUX_ORGANIZER_TOKEN", "8719431297"), ("BORDEAUX_EVENTBRITE_TOKEN", "LUFCAZRS"),
+        ])).unwrap();
+        assert_eq!(config.http.port, 8088);
+        assert_eq!(config.limits.max_draw, 10);
+        assert_eq!(config.shutdown.timeout, 5);
+        assert_eq!(config.http.cors_allowed_origins, vec!["https://jug-montpellier.org".to_string()]);
+        assert_eq!(config.tenants, vec![TenantConfig { name: "bordeaux".to_string(), organizer: "8719431297".to_string(), token: "LUFCAZRS".to_string(), webhook_secret: None }]);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(toml::from_str::<Config>("[http]\nprot = 9000").is_err());
+
+        let mut config = Config::default();
+        match config.apply_env(env(&[("HTTP_PORT", "eighty"), ("MAX_DRAW_COUNT", "-1")])) {
+            Err(ConfigError::Invalid(problems)) => assert_eq!(problems, "HTTP_PORT has an invalid value : eighty\n - MAX_DRAW_COUNT has an invalid value : -1"),
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        config.refresh.interval = 60;
+        config.refresh.idle_interval = 30;
+        config.auth.admin_tokens = vec!["francois".to_string()];
+        config.shutdown.timeout = 0;
+        match config.validate() {
+            Err(ConfigError::Invalid(problems)) => {
+                assert!(problems.contains("database.url (DATABASE_URL) is mandatory"));
+                assert!(problems.contains("refresh.cache_max_age (CACHE_MAX_AGE) must be greater than the refresh interval (60s)"));
+                assert!(problems.contains("refresh.idle_interval (REFRESH_IDLE_INTERVAL) can't be shorter than the refresh interval (60s)"));
+                assert!(problems.contains("auth.admin_tokens (ADMIN_TOKENS) : Invalid api key entry `francois`"));
+                assert!(problems.contains("shutdown.timeout (SHUTDOWN_TIMEOUT) must be at least 1 second"));
+                assert!(problems.contains("No tenant configured"));
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reload() {
+        let config: Config = toml::from_str(FILE).unwrap();
+        let shared = SharedConfig::new(config.clone());
+
+        let mut rotated = config.clone();
+        rotated.tenants[0].token = "LUFCAZRS".to_string();
+        rotated.http.port = 9001;
+        assert_eq!(shared.reload(rotated), Ok(Reloaded { updated_tenants: vec!["bordeaux".to_string()], restart_required: vec!["http".to_string()] }));
+        assert_eq!(shared.tenant("bordeaux").unwrap().token, "LUFCAZRS");
+        assert_eq!(shared.0.read().unwrap().http.port, 9000);
+
+        let mut added = config.clone();
+        added.tenants.push(TenantConfig { name: "toulouse".to_string(), organizer: "8719431297".to_string(), token: "5O5ICDI5".to_string(), webhook_secret: None });
+        assert!(shared.reload(added).is_err());
+        assert_eq!(shared.tenant("toulouse"), None);
+    }
+}
@@ -3,86 +3,466 @@ use actix::prelude::*;
 use actix::SyncArbiter;
 use diesel;
 use diesel::prelude::*;
-use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
+use diesel_migrations::MigrationConnection;
+use failure::{Error, ResultExt};
+use std::sync::{Arc, Barrier};
+use std::time::Duration;
 use uuid;
-use schema::winners;
+use chrono::Utc;
+use eventbrite::Profile;
+use repository::{self, ApiToken, Draw, NewDraw, NewWinner, Winner};
+use schemars::JsonSchema;
+use auth::Role;
 use LotteryError;
-use failure::Error;
 
-/// This is db executor actor. We are going to run 3 of them in parallel.
-pub struct DbExecutor(pub Pool<ConnectionManager<SqliteConnection>>);
+#[cfg(all(feature = "sqlite", feature = "postgres"))]
+compile_error!("features `sqlite` and `postgres` are mutually exclusive, use `--no-default-features --features postgres`");
+#[cfg(not(any(feature = "sqlite", feature = "postgres")))]
+compile_error!("a database backend feature is mandatory : `sqlite` or `postgres`");
 
-/// This is only message that this actor can handle, but it is easy to extend
-/// number of messages.
-#[derive(Serialize, Deserialize)]
+/// Connection of the database backend selected at compile time
+#[cfg(feature = "sqlite")]
+pub type DbConnection = diesel::sqlite::SqliteConnection;
+#[cfg(feature = "postgres")]
+pub type DbConnection = diesel::pg::PgConnection;
+
+/// This is db executor actor. `DatabaseConfig::executors` of them run in parallel.
+pub struct DbExecutor(pub Pool<ConnectionManager<DbConnection>>);
+
+/// Connection pool and executors settings
+pub struct DatabaseConfig {
+    pub url: String,
+    pub pool_size: u32,
+    pub connection_timeout: Duration,
+    pub executors: usize,
+}
+
+impl DbExecutor {
+    /// Borrow a connection from the pool, an exhausted pool is reported as an unavailable database
+    fn connection(&self) -> Result<PooledConnection<ConnectionManager<DbConnection>>, LotteryError> {
+        self.0.get().map_err(|error| LotteryError::DatabaseUnavailable { cause: error.into() })
+    }
+}
+
+/// Record a new winner for an event
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct CreateWinner {
+    #[serde(skip_deserializing)]
+    #[schemars(skip)]
+    pub tenant: String,
     pub first_name: String,
     pub last_name: String,
-    pub event_id: Option<String>
+    pub event_id: Option<String>,
+    /// Record a person who is neither an attendee nor drawn, admins only
+    #[serde(default)]
+    pub force: bool,
 }
 
 impl Message for CreateWinner {
-    type Result = Result<Winner, Error>;
+    type Result = Result<Winner, LotteryError>;
+}
+
+/// Persist a draw and its winners
+pub struct CreateDraw {
+    pub tenant: String,
+    pub event_id: String,
+    pub author: String,
+    pub seed: Option<u64>,
+    pub prize: Option<String>,
+    pub winners: Vec<Profile>,
+}
+
+impl Message for CreateDraw {
+    type Result = Result<Draw, LotteryError>;
+}
+
+/// Record the winner of a draw result
+pub struct RecordDrawResult {
+    pub tenant: String,
+    pub id: String,
+}
+
+impl Message for RecordDrawResult {
+    type Result = Result<Winner, LotteryError>;
+}
+
+/// Check a person was drawn, during the given event when it is known
+pub struct IsDrawn {
+    pub tenant: String,
+    pub event_id: Option<String>,
+    pub first_name: String,
+    pub last_name: String,
+}
+
+impl Message for IsDrawn {
+    type Result = Result<bool, LotteryError>;
+}
+
+/// Fix the name of an already recorded winner
+#[derive(Deserialize, JsonSchema)]
+pub struct UpdateWinner {
+    #[serde(skip_deserializing)]
+    #[schemars(skip)]
+    pub tenant: String,
+    #[serde(skip_deserializing)]
+    #[schemars(skip)]
+    pub id: String,
+    pub first_name: String,
+    pub last_name: String,
+    #[serde(skip_deserializing)]
+    #[schemars(skip)]
+    pub author: String,
+}
+
+impl Message for UpdateWinner {
+    type Result = Result<Winner, LotteryError>;
+}
+
+/// Soft delete a recorded winner, the row is kept for the audit trail
+pub struct DeleteWinner {
+    pub tenant: String,
+    pub id: String,
+    pub author: String,
+}
+
+impl Message for DeleteWinner {
+    type Result = Result<Winner, LotteryError>;
+}
+
+/// Create a token of the tenant, its secret is only returned in the response
+#[derive(Deserialize, JsonSchema)]
+pub struct CreateToken {
+    #[serde(skip_deserializing)]
+    #[schemars(skip)]
+    pub tenant: String,
+    pub name: String,
+    pub role: Role,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct CreatedToken {
+    #[serde(flatten)]
+    pub token: ApiToken,
+    pub secret: String,
+}
+
+impl Message for CreateToken {
+    type Result = Result<CreatedToken, LotteryError>;
+}
+
+pub struct ListTokens {
+    pub tenant: String,
+}
+
+impl Message for ListTokens {
+    type Result = Result<Vec<ApiToken>, LotteryError>;
+}
+
+/// Look for a valid token matching a secret
+pub struct FindToken {
+    pub secret: String,
+}
+
+impl Message for FindToken {
+    type Result = Result<Option<ApiToken>, LotteryError>;
+}
+
+pub struct RevokeToken {
+    pub tenant: String,
+    pub id: String,
+}
+
+impl Message for RevokeToken {
+    type Result = Result<ApiToken, LotteryError>;
+}
+
+/// Version of the last migration applied on the database
+pub struct GetSchemaVersion;
+
+impl Message for GetSchemaVersion {
+    type Result = Result<Option<String>, LotteryError>;
+}
+
+/// Wait for the other executors at the barrier : once every executor handled one, the messages sent before are done
+pub struct Drain {
+    pub barrier: Arc<Barrier>,
+}
+
+impl Message for Drain {
+    type Result = ();
 }
 
 impl Actor for DbExecutor {
     type Context = SyncContext<Self>;
 }
 
+impl From<diesel::result::Error> for LotteryError {
+    fn from(error: diesel::result::Error) -> Self {
+        LotteryError::UnexpectedError { cause: error.into() }
+    }
+}
+
+impl Handler<Drain> for DbExecutor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Drain, _: &mut Self::Context) -> Self::Result {
+        msg.barrier.wait();
+    }
+}
+
 impl Handler<CreateWinner> for DbExecutor {
-    type Result = Result<Winner, Error>;
+    type Result = Result<Winner, LotteryError>;
 
     fn handle(&mut self, msg: CreateWinner, _: &mut Self::Context) -> Self::Result {
-        use schema::winners::dsl::*;
-        let uuid = format!("{}", uuid::Uuid::new_v4());
-        let new_user = NewWinner {
-            id: &uuid,
+        let id = format!("{}", uuid::Uuid::new_v4());
+        let event_id = msg.event_id.unwrap_or("Unknown".to_owned());
+        let conn = self.connection()?;
+        repository::insert_winner(&conn, &NewWinner {
+            id: &id,
             first_name: &msg.first_name,
             last_name: &msg.last_name,
-            event_id: &msg.event_id.unwrap_or("Unknown".to_owned()),
-        };
+            event_id: &event_id,
+            tenant: &msg.tenant,
+        })
+    }
+}
 
-        let conn: &SqliteConnection = &self.0.get().unwrap();
+impl Handler<CreateDraw> for DbExecutor {
+    type Result = Result<Draw, LotteryError>;
 
-        diesel::insert_into(winners)
-            .values(&new_user)
-            .execute(conn)
-            .map_err(|err| { LotteryError::UnexpectedError { cause: err.into() } })?;
+    fn handle(&mut self, msg: CreateDraw, _: &mut Self::Context) -> Self::Result {
+        let id = format!("{}", uuid::Uuid::new_v4());
+        let conn = self.connection()?;
+        repository::insert_draw(&conn, &NewDraw {
+            id: &id,
+            event_id: &msg.event_id,
+            author: &msg.author,
+            seed: msg.seed.map(|seed| seed as i64),
+            prize: msg.prize.as_ref().map(|prize| prize.as_ref()),
+            drawn_at: Utc::now().naive_utc(),
+            tenant: &msg.tenant,
+        }, &msg.winners)
+    }
+}
 
-        let mut items = winners
-            .filter(id.eq(&uuid))
-            .load::<Winner>(conn)
-            .map_err(|err| { LotteryError::UnexpectedError { cause: err.into() } })?;
+impl Handler<RecordDrawResult> for DbExecutor {
+    type Result = Result<Winner, LotteryError>;
 
-        Ok(items.pop().unwrap())
+    fn handle(&mut self, msg: RecordDrawResult, _: &mut Self::Context) -> Self::Result {
+        let conn = self.connection()?;
+        repository::record_draw_result(&conn, &msg.tenant, &msg.id)
     }
 }
 
+impl Handler<IsDrawn> for DbExecutor {
+    type Result = Result<bool, LotteryError>;
 
-#[derive(Serialize, Queryable, Debug)]
-pub struct Winner {
-    pub id: String,
-    pub first_name: String,
-    pub last_name: String,
-    pub event_id: String
+    fn handle(&mut self, msg: IsDrawn, _: &mut Self::Context) -> Self::Result {
+        let conn = self.connection()?;
+        repository::is_drawn(&conn, &msg.tenant, msg.event_id.as_ref().map(|event_id| event_id.as_ref()), &msg.first_name, &msg.last_name)
+    }
+}
+
+impl Handler<UpdateWinner> for DbExecutor {
+    type Result = Result<Winner, LotteryError>;
+
+    fn handle(&mut self, msg: UpdateWinner, _: &mut Self::Context) -> Self::Result {
+        let conn = self.connection()?;
+        repository::update_winner(&conn, &msg.tenant, &msg.id, &msg.first_name, &msg.last_name, &msg.author)
+    }
+}
+
+impl Handler<DeleteWinner> for DbExecutor {
+    type Result = Result<Winner, LotteryError>;
+
+    fn handle(&mut self, msg: DeleteWinner, _: &mut Self::Context) -> Self::Result {
+        let conn = self.connection()?;
+        repository::delete_winner(&conn, &msg.tenant, &msg.id, &msg.author)
+    }
+}
+
+impl Handler<CreateToken> for DbExecutor {
+    type Result = Result<CreatedToken, LotteryError>;
+
+    fn handle(&mut self, msg: CreateToken, _: &mut Self::Context) -> Self::Result {
+        let conn = self.connection()?;
+        repository::create_token(&conn, &msg.tenant, &msg.name, msg.role)
+            .map(|(token, secret)| CreatedToken { token, secret })
+    }
+}
+
+impl Handler<ListTokens> for DbExecutor {
+    type Result = Result<Vec<ApiToken>, LotteryError>;
+
+    fn handle(&mut self, msg: ListTokens, _: &mut Self::Context) -> Self::Result {
+        let conn = self.connection()?;
+        repository::list_tokens(&conn, &msg.tenant)
+    }
+}
+
+impl Handler<FindToken> for DbExecutor {
+    type Result = Result<Option<ApiToken>, LotteryError>;
+
+    fn handle(&mut self, msg: FindToken, _: &mut Self::Context) -> Self::Result {
+        let conn = self.connection()?;
+        repository::find_token(&conn, &msg.secret)
+    }
+}
+
+impl Handler<RevokeToken> for DbExecutor {
+    type Result = Result<ApiToken, LotteryError>;
+
+    fn handle(&mut self, msg: RevokeToken, _: &mut Self::Context) -> Self::Result {
+        let conn = self.connection()?;
+        repository::revoke_token(&conn, &msg.tenant, &msg.id)
+    }
 }
 
-#[derive(Insertable)]
-#[table_name = "winners"]
-pub struct NewWinner<'a> {
-    pub id: &'a str,
-    pub first_name: &'a str,
-    pub last_name: &'a str,
-    pub event_id: &'a str
+impl Handler<GetSchemaVersion> for DbExecutor {
+    type Result = Result<Option<String>, LotteryError>;
+
+    fn handle(&mut self, _msg: GetSchemaVersion, _: &mut Self::Context) -> Self::Result {
+        let pooled = self.connection()?;
+        let conn: &DbConnection = &pooled;
+        Ok(conn.latest_run_migration_version()?)
+    }
 }
 
 embed_migrations!("migrations");
 
-pub fn start_database(database_url: String) -> Addr<DbExecutor> {
-    let manager = ConnectionManager::<SqliteConnection>::new(database_url);
+/// Connect to the database and apply the pending migrations, the connection is returned for the commands
+pub fn migrate(url: &str) -> Result<DbConnection, Error> {
+    let conn = DbConnection::establish(url).context("Failed to connect to database")?;
+    embedded_migrations::run(&conn).context("Failed to apply database migrations")?;
+    let version = conn.latest_run_migration_version().context("Failed to read database schema version")?;
+    info!("Database schema up to date (version {})", version.unwrap_or("none".to_owned()));
+    Ok(conn)
+}
+
+/// Apply the pending migrations then create the connection pool and start the executors
+pub fn start_database(config: DatabaseConfig) -> Result<Addr<DbExecutor>, Error> {
+    migrate(&config.url)?;
+
+    let manager = ConnectionManager::<DbConnection>::new(config.url);
     let pool = diesel::r2d2::Pool::builder()
+        .max_size(config.pool_size)
+        .connection_timeout(config.connection_timeout)
         .build(manager)
-        .expect("Failed to create pool.");
+        .context("Failed to create pool")?;
+
+    Ok(SyncArbiter::start(config.executors, move || DbExecutor(pool.clone())))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use actix_web::error::ResponseError;
+    use actix_web::http::StatusCode;
 
-    SyncArbiter::start(3, move || DbExecutor(pool.clone()))
-}
\ No newline at end of file
+    /// Temporary database for tests : in memory for sqlite, `TEST_DATABASE_URL` for postgres
+    #[cfg(feature = "sqlite")]
+    fn test_database_url() -> String {
+        ":memory:".to_owned()
+    }
+
+    #[cfg(feature = "postgres")]
+    fn test_database_url() -> String {
+        ::std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is mandatory for postgres tests")
+    }
+
+    /// Migrated connection inside a transaction never committed, nothing is kept after the test
+    pub fn test_connection() -> DbConnection {
+        let conn = DbConnection::establish(&test_database_url()).unwrap();
+        conn.begin_test_transaction().unwrap();
+        embedded_migrations::run(&conn).unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_migrations() {
+        let conn = test_connection();
+        assert!(conn.latest_run_migration_version().unwrap().is_some());
+
+        let count = ::schema::winners::table.count().get_result::<i64>(&conn);
+        assert_eq!(count, Ok(0));
+    }
+
+    /// Pool of a single in memory connection, migrated like the real database
+    #[cfg(feature = "sqlite")]
+    fn test_pool() -> Pool<ConnectionManager<DbConnection>> {
+        let manager = ConnectionManager::<DbConnection>::new(":memory:");
+        let pool = Pool::builder().max_size(1).build(manager).unwrap();
+        embedded_migrations::run(&*pool.get().unwrap()).unwrap();
+        pool
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn test_update_and_delete_winner() {
+        let mut system = System::new("test");
+        let pool = test_pool();
+        let db = {
+            let pool = pool.clone();
+            SyncArbiter::start(1, move || DbExecutor(pool.clone()))
+        };
+
+        let created = system.block_on(db.send(CreateWinner { tenant: "devfest".to_owned(), first_name: "Ada".to_owned(), last_name: "Byron".to_owned(), event_id: None, force: false }))
+            .unwrap().unwrap();
+        let updated = system.block_on(db.send(UpdateWinner { tenant: "devfest".to_owned(), id: created.id.clone(), first_name: "Ada".to_owned(), last_name: "Lovelace".to_owned(), author: "presenter".to_owned() }))
+            .unwrap().unwrap();
+        assert_eq!(updated.id, created.id);
+        assert_eq!(updated.last_name, "Lovelace");
+
+        let deleted = system.block_on(db.send(DeleteWinner { tenant: "devfest".to_owned(), id: created.id.clone(), author: "presenter".to_owned() }))
+            .unwrap().unwrap();
+        assert_eq!(deleted.last_name, "Lovelace");
+        assert!(matches!(system.block_on(db.send(DeleteWinner { tenant: "devfest".to_owned(), id: created.id.clone(), author: "presenter".to_owned() })).unwrap(),
+            Err(LotteryError::WinnerNotFound { .. })));
+        assert!(matches!(system.block_on(db.send(UpdateWinner { tenant: "devfest".to_owned(), id: created.id.clone(), first_name: "Ada".to_owned(), last_name: "King".to_owned(), author: "presenter".to_owned() })).unwrap(),
+            Err(LotteryError::WinnerNotFound { .. })));
+
+        let conn = pool.get().unwrap();
+        let mut actions = ::schema::winner_audits::table.select(::schema::winner_audits::action).load::<String>(&*conn).unwrap();
+        actions.sort();
+        assert_eq!(actions, vec!["delete".to_owned(), "update".to_owned()]);
+        let remaining = ::schema::winners::table.filter(::schema::winners::deleted.eq(false)).count().get_result::<i64>(&*conn);
+        assert_eq!(remaining, Ok(0));
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn test_record_draw_result_twice() {
+        let mut system = System::new("test");
+        let pool = test_pool();
+        let db = SyncArbiter::start(1, move || DbExecutor(pool.clone()));
+
+        let draw = system.block_on(db.send(CreateDraw {
+            tenant: "devfest".to_owned(),
+            event_id: "51124390428".to_owned(),
+            author: "presenter".to_owned(),
+            seed: None,
+            prize: None,
+            winners: vec![Profile { first_name: "Ada".to_owned(), last_name: "Lovelace".to_owned() }],
+        })).unwrap().unwrap();
+        let record = || RecordDrawResult { tenant: "devfest".to_owned(), id: draw.results[0].id.clone() };
+
+        assert!(system.block_on(db.send(record())).unwrap().is_ok());
+        let error = system.block_on(db.send(record())).unwrap().unwrap_err();
+        assert_eq!(ResponseError::error_response(&error).status(), StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn test_connection_pool_exhausted() {
+        let manager = ConnectionManager::<DbConnection>::new(test_database_url());
+        let pool = Pool::builder()
+            .max_size(1)
+            .connection_timeout(Duration::from_millis(10))
+            .build(manager)
+            .unwrap();
+        let executor = DbExecutor(pool);
+
+        let _borrowed = executor.connection().unwrap();
+        assert!(matches!(executor.connection(), Err(LotteryError::DatabaseUnavailable { .. })));
+    }
+}
@@ -1,7 +1,11 @@
+use chrono::{DateTime, Utc};
 use failure::Error;
 use reqwest;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer};
+use serde_json::{self, Value};
 
-const EVENTBRITE_BASE_URL: &'static str = "https://www.eventbriteapi.com";
+const EVENTBRITE_BASE_URL: &str = "https://www.eventbriteapi.com";
 
 #[derive(Debug, Fail)]
 pub enum EventbriteError {
@@ -15,6 +19,7 @@ pub enum EventbriteError {
 }
 
 #[derive(Deserialize, Debug, Clone)]
+#[allow(dead_code)]
 pub struct Pagination {
     pub object_count: u8,
     pub page_count: u8,
@@ -22,15 +27,35 @@ pub struct Pagination {
     pub page_number: u8,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
 pub struct Profile {
     pub first_name: String,
     pub last_name: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// Form of a name compared to find a person, the attendees and the draws must match names the same way
+pub fn normalize_name(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct Attende {
-    pub profile: Profile
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub event_id: String,
+    pub profile: Profile,
+    #[serde(default)]
+    pub cancelled: bool,
+    #[serde(default)]
+    pub refunded: bool,
+}
+
+impl Attende {
+    /// Cancelled and refunded attendees can't win
+    pub fn is_attending(&self) -> bool {
+        !self.cancelled && !self.refunded
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -39,9 +64,24 @@ pub struct AttendeesResponse {
     pub pagination: Pagination,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
 pub struct Event {
-    pub id: String
+    pub id: String,
+    #[serde(default, deserialize_with = "utc_time")]
+    pub start: Option<DateTime<Utc>>,
+    #[serde(default, deserialize_with = "utc_time")]
+    pub end: Option<DateTime<Utc>>,
+}
+
+/// Date of an event in eventbrite, with its local time and timezone
+#[derive(Deserialize)]
+struct EventTime {
+    utc: DateTime<Utc>,
+}
+
+/// Eventbrite gives `{"timezone", "local", "utc"}`, the events of the api only keep the utc date
+fn utc_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+    Option::<EventTime>::deserialize(deserializer).map(|time| time.map(|time| time.utc))
 }
 
 #[derive(Deserialize, Debug)]
@@ -63,7 +103,7 @@ fn load_events(organizer: &str, token: &str) -> Result<EventsResponse, Error> {
 
 fn first_event(events: EventsResponse) -> Result<Event, Error> {
     events.events.first()
-            .map(|reference| reference.clone())
+            .cloned()
             .ok_or(EventbriteError::NoEventAvailable.into())
 }
 
@@ -75,6 +115,11 @@ pub fn get_current_event(organizer: &str, token: &str) -> Result<Event, Error> {
     fetch_first_event(load_events, organizer, token)
 }
 
+/// Live events of the organizer, the most recent first
+pub fn list_events(organizer: &str, token: &str) -> Result<Vec<Event>, Error> {
+    load_events(organizer, token).map(|response| response.events)
+}
+
 fn attendees_url(event_id: &str, token: &str, page_id: u8) -> String {
     format!("{base_url}/v3/events/{event_id}/attendees/?token={token}&page={page}", base_url = EVENTBRITE_BASE_URL, event_id = event_id, token = token, page = page_id)
 }
@@ -90,7 +135,7 @@ fn fetch_all_attendees<F: Fn(&str, &str, u8) -> Result<AttendeesResponse, Error>
     fetch: F,
     event_id: &str,
     token: &str,
-) -> Result<Vec<Profile>, Error> {
+) -> Result<Vec<Attende>, Error> {
     fetch(event_id, token, 0)
         .and_then(|result| {
             (result.pagination.page_number..result.pagination.page_count)
@@ -105,7 +150,7 @@ fn fetch_all_attendees<F: Fn(&str, &str, u8) -> Result<AttendeesResponse, Error>
             results
                 .into_iter()
                 .flat_map(|response| response.attendees)
-                .map(|attendee| attendee.profile)
+                .filter(|attendee| attendee.is_attending())
                 .collect()
         })
         .map_err(|err| {
@@ -117,13 +162,102 @@ fn fetch_all_attendees<F: Fn(&str, &str, u8) -> Result<AttendeesResponse, Error>
         })
 }
 
-pub fn load_attendees(event_id: &str, token: &str) -> Result<Vec<Profile>, Error> {
+/// Attendees of the event, without the cancelled and refunded ones
+pub fn load_attendees(event_id: &str, token: &str) -> Result<Vec<Attende>, Error> {
     fetch_all_attendees(fetch_attendees_page, event_id, token)
 }
 
+/// Notification of an eventbrite webhook, the changed resource is loaded from `api_url`
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct WebhookPayload {
+    pub config: WebhookConfig,
+    pub api_url: String,
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct WebhookConfig {
+    /// `order.placed`, `attendee.updated` or `attendee.checked_in`, the other actions are ignored
+    pub action: String,
+    #[serde(default)]
+    pub webhook_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Order {
+    attendees: Vec<Attende>,
+}
+
+/// The resources of a webhook are only loaded from the eventbrite api, the token is sent with the request
+pub fn is_api_url(url: &str) -> bool {
+    url.starts_with(&format!("{}/v3/", EVENTBRITE_BASE_URL)) && !url.contains(['?', '#', '@']) && !url.contains("..")
+}
+
+/// Hide the token in an error message : eventbrite errors contain the request url, with the token in the query
+pub fn redact_token(message: &str, token: &str) -> String {
+    if token.is_empty() {
+        return message.to_owned();
+    }
+    message.replace(token, "***")
+}
+
+fn fetch_json(url: &str) -> Result<Value, Error> {
+    let resource = reqwest::get(url)?
+        .error_for_status()?
+        .json()?;
+    Ok(resource)
+}
+
+fn fetch_webhook_attendees<F: Fn(&str) -> Result<Value, Error>>(fetch: F, payload: &WebhookPayload, token: &str) -> Result<Option<Vec<Attende>>, Error> {
+    match payload.config.action.as_ref() {
+        "order.placed" => fetch(&format!("{}?expand=attendees&token={}", payload.api_url, token))
+            .and_then(|order| Ok(Some(serde_json::from_value::<Order>(order)?.attendees))),
+        "attendee.updated" | "attendee.checked_in" => fetch(&format!("{}?token={}", payload.api_url, token))
+            .and_then(|attendee| Ok(Some(vec![serde_json::from_value::<Attende>(attendee)?]))),
+        _ => Ok(None),
+    }
+}
+
+/// Attendees changed by the action of a webhook, `None` for the actions without attendees
+pub fn load_webhook_attendees(payload: &WebhookPayload, token: &str) -> Result<Option<Vec<Attende>>, Error> {
+    fetch_webhook_attendees(fetch_json, payload, token)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use chrono::TimeZone;
+    use test_helpers::attendee;
+
+    fn event(id: &str) -> Event {
+        Event { id: id.to_string(), start: None, end: None }
+    }
+
+    #[test]
+    fn test_normalize_name() {
+        assert_eq!(normalize_name(" Émile  "), normalize_name("ÉMILE"));
+        assert_eq!(normalize_name("Jean-Luc"), "jean-luc");
+    }
+
+    #[test]
+    fn test_redact_token() {
+        let message = "https://www.eventbriteapi.com/v3/orders/1081354297/?expand=attendees&token=5O5ICDI5I4LUFCAZRSTX: error trying to connect";
+        assert_eq!(redact_token(message, "5O5ICDI5I4LUFCAZRSTX"), "https://www.eventbriteapi.com/v3/orders/1081354297/?expand=attendees&token=***: error trying to connect");
+        assert_eq!(redact_token(message, ""), message);
+    }
+
+    #[test]
+    fn test_deserialize_events() {
+        let response: EventsResponse = serde_json::from_str(r#"{"events": [{
+            "id": "52097259305",
+            "name": {"text": "Soirée Rust"},
+            "start": {"timezone": "Europe/Paris", "local": "2026-10-20T19:00:00", "utc": "2026-10-20T17:00:00Z"},
+            "end": {"timezone": "Europe/Paris", "local": "2026-10-20T22:00:00", "utc": "2026-10-20T20:00:00Z"}
+        }, {"id": "51124390428"}]}"#).unwrap();
+        assert_eq!(response.events, vec![
+            Event { id: "52097259305".to_string(), start: Some(Utc.with_ymd_and_hms(2026, 10, 20, 17, 0, 0).unwrap()), end: Some(Utc.with_ymd_and_hms(2026, 10, 20, 20, 0, 0).unwrap()) },
+            event("51124390428"),
+        ]);
+    }
 
     #[test]
     fn test_attendees_url() {
@@ -138,20 +272,20 @@ mod tests {
 
     #[test]
     fn test_first_event() {
-        let response = EventsResponse { events: vec![Event { id: "51124390428".to_string() }] };
+        let response = EventsResponse { events: vec![event("51124390428")] };
         let actual = first_event(response);
         assert!(actual.is_ok());
-        assert_eq!(actual.unwrap(), Event { id: "51124390428".to_string() });
+        assert_eq!(actual.unwrap(), event("51124390428"));
 
         let response = EventsResponse { events: vec![] };
         let actual = first_event(response);
         assert!(actual.is_err());
         matches!(actual.unwrap_err().downcast::<EventbriteError>(), Ok(EventbriteError::NoEventAvailable));
 
-        let response = EventsResponse { events: vec![Event { id: "51124390432".to_string() }, Event { id: "51124390428".to_string() }] };
+        let response = EventsResponse { events: vec![event("51124390432"), event("51124390428")] };
         let actual = first_event(response);
         assert!(actual.is_ok());
-        assert_eq!(actual.unwrap(), Event { id: "51124390432".to_string() });
+        assert_eq!(actual.unwrap(), event("51124390432"));
     }
 
     #[test]
@@ -160,11 +294,11 @@ mod tests {
         use std::io::ErrorKind;
 
         let fetch = |_organizer: &str, _token: &str| {
-            Ok(EventsResponse { events: vec![Event { id: "51124390428".to_string() }] })
+            Ok(EventsResponse { events: vec![event("51124390428")] })
         };
         let actual = fetch_first_event(fetch, "412451CDS", "5O5ICDI5I4LUFCAZRSTX");
         assert!(actual.is_ok());
-        assert_eq!(actual.unwrap(), Event { id: "51124390428".to_string() });
+        assert_eq!(actual.unwrap(), event("51124390428"));
 
         let fetch = |_organizer: &str, _token: &str| {
             Ok(EventsResponse { events: vec![] })
@@ -181,11 +315,52 @@ mod tests {
         matches!(actual.unwrap_err().downcast::<Error>(), Ok(ref e) if e.kind() == ErrorKind::ConnectionRefused);
 
         let fetch = |_organizer: &str, _token: &str| {
-            Ok(EventsResponse { events: vec![Event { id: "51124390432".to_string() }, Event { id: "51124390428".to_string() }] })
+            Ok(EventsResponse { events: vec![event("51124390432"), event("51124390428")] })
         };
         let actual = fetch_first_event(fetch, "412451CDS", "5O5ICDI5I4LUFCAZRSTX");
         assert!(actual.is_ok());
-        assert_eq!(actual.unwrap(), Event { id: "51124390432".to_string() });
+        assert_eq!(actual.unwrap(), event("51124390432"));
+    }
+
+    /// Answer the recorded resource of the api url, without the query
+    fn recorded(url: &str) -> Result<Value, Error> {
+        let resource = match url.split('?').next().unwrap() {
+            "https://www.eventbriteapi.com/v3/orders/1081354297/" => include_str!("../fixtures/eventbrite/order.json"),
+            "https://www.eventbriteapi.com/v3/events/52097259305/attendees/1245667834/" => include_str!("../fixtures/eventbrite/attendee_cancelled.json"),
+            "https://www.eventbriteapi.com/v3/events/52097259305/attendees/1245667901/" => include_str!("../fixtures/eventbrite/attendee_checked_in.json"),
+            other => bail!("No recorded resource for {}", other),
+        };
+        Ok(serde_json::from_str(resource)?)
+    }
+
+    #[test]
+    fn test_fetch_webhook_attendees() {
+        let payload = |recorded: &str| serde_json::from_str::<WebhookPayload>(recorded).unwrap();
+
+        let order_placed = payload(include_str!("../fixtures/eventbrite/webhook_order_placed.json"));
+        assert_eq!(order_placed.config.action, "order.placed");
+        assert_eq!(fetch_webhook_attendees(recorded, &order_placed, "5O5ICDI5I4LUFCAZRSTX").unwrap(),
+                   Some(vec![attendee("1245667833", "Ada", "Lovelace"), attendee("1245667834", "Grace", "Hopper")]));
+
+        let updated = payload(include_str!("../fixtures/eventbrite/webhook_attendee_updated.json"));
+        assert_eq!(fetch_webhook_attendees(recorded, &updated, "5O5ICDI5I4LUFCAZRSTX").unwrap(),
+                   Some(vec![Attende { cancelled: true, ..attendee("1245667834", "Grace", "Hopper") }]));
+
+        let checked_in = payload(include_str!("../fixtures/eventbrite/webhook_attendee_checked_in.json"));
+        assert_eq!(fetch_webhook_attendees(recorded, &checked_in, "5O5ICDI5I4LUFCAZRSTX").unwrap(),
+                   Some(vec![attendee("1245667901", "Margaret", "Hamilton")]));
+
+        let test = WebhookPayload { config: WebhookConfig { action: "test".to_string(), webhook_id: "5137451".to_string() }, ..order_placed };
+        assert_eq!(fetch_webhook_attendees(recorded, &test, "5O5ICDI5I4LUFCAZRSTX").unwrap(), None);
+    }
+
+    #[test]
+    fn test_is_api_url() {
+        assert!(is_api_url("https://www.eventbriteapi.com/v3/orders/1081354297/"));
+        assert!(!is_api_url("https://www.eventbriteapi.com.attacker.org/v3/orders/1081354297/"));
+        assert!(!is_api_url("https://attacker.org/v3/orders/1081354297/"));
+        assert!(!is_api_url("https://www.eventbriteapi.com/v3/orders/1081354297/?token=5O5ICDI5I4LUFCAZRSTX"));
+        assert!(!is_api_url("https://www.eventbriteapi.com/v3/../../orders/"));
     }
 
     #[test]
@@ -219,7 +394,7 @@ mod tests {
         let typed_error = result.unwrap_err().downcast::<EventbriteError>().unwrap();
         match typed_error {
             EventbriteError::AttendeesLoadError { event_id: _, cause } => assert_eq!(cause.downcast::<Error>().unwrap().kind(), ErrorKind::ConnectionRefused),
-            _ => assert!(false)
+            _ => panic!("Unexpected error type")
         }
 
         // Err on pagination loading
@@ -243,15 +418,7 @@ mod tests {
         let typed_error = result.unwrap_err().downcast::<EventbriteError>().unwrap();
         match typed_error {
             EventbriteError::AttendeesLoadError { event_id: _, cause } => assert_eq!(cause.downcast::<Error>().unwrap().kind(), ErrorKind::ConnectionRefused),
-            _ => assert!(false)
-        }
-    }
-
-    #[derive(Debug, Fail, PartialEq)]
-    enum EventbriteTestError {
-        #[fail(display = "Unexpected Error for tests")]
-        TestError {
-            page: u8
+            _ => panic!("Unexpected error type")
         }
     }
 }
\ No newline at end of file
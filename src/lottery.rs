@@ -1,29 +1,54 @@
 use eventbrite::Profile;
 use failure::Error;
-use rand::{seq, thread_rng};
+use rand::{seq, thread_rng, Rng, SeedableRng};
+use rand::prng::ChaChaRng;
 
 #[derive(Debug, Fail, PartialEq)]
 enum DrawError {
-    #[fail(display = "Invalid draw request (asked : {})", asked)]
-    InvalidDrawRequest {
-        asked: i8
-    },
     #[fail(display = "Not enough participants (asked: {}, existing: {})", asked, existant)]
     NotEnoughtParticipant {
-        asked: i8,
+        asked: usize,
         existant: usize,
     }
 }
 
-pub fn draw(nb: i8, attendees: &Vec<Profile>) -> Result<Vec<&Profile>, Error> {
-    match nb {
-        a if a < 0 => Err(DrawError::InvalidDrawRequest { asked: a }.into()),
-        0 => Ok(vec![]),
-        _ => {
-            let mut rng = thread_rng();
-            seq::sample_iter(&mut rng, attendees, nb as usize)
-                .map_err(|_| DrawError::NotEnoughtParticipant { asked: nb, existant: attendees.len() }.into())
-        }
+/// Parameters of a draw, validated by the web layer
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DrawRequest {
+    /// Number of winners
+    pub nb: usize,
+    /// Full names of the attendees who can't win, compared without case
+    pub excluded: Vec<String>,
+    /// Seed of the draw, the same seed on the same attendees gives the same winners
+    pub seed: Option<u64>,
+    /// What the winners get, shown on the screens
+    pub prize: Option<String>,
+}
+
+impl DrawRequest {
+    fn is_eligible(&self, attendee: &Profile) -> bool {
+        let name = format!("{} {}", attendee.first_name, attendee.last_name).to_lowercase();
+        !self.excluded.iter().any(|excluded| excluded.to_lowercase() == name)
+    }
+}
+
+/// Named algorithm rather than `StdRng`, which may change with rand versions, so a seed keeps drawing the same winners
+fn seeded_rng(seed: u64) -> ChaChaRng {
+    let mut bytes = [0u8; 32];
+    bytes[..8].copy_from_slice(&seed.to_le_bytes());
+    ChaChaRng::from_seed(bytes)
+}
+
+fn sample<'a, R: Rng>(rng: &mut R, request: &DrawRequest, attendees: &'a [Profile]) -> Result<Vec<&'a Profile>, Error> {
+    let eligible: Vec<&Profile> = attendees.iter().filter(|attendee| request.is_eligible(attendee)).collect();
+    seq::sample_iter(rng, eligible.iter().cloned(), request.nb)
+        .map_err(|_| DrawError::NotEnoughtParticipant { asked: request.nb, existant: eligible.len() }.into())
+}
+
+pub fn draw<'a>(request: &DrawRequest, attendees: &'a [Profile]) -> Result<Vec<&'a Profile>, Error> {
+    match request.seed {
+        Some(seed) => sample(&mut seeded_rng(seed), request, attendees),
+        None => sample(&mut thread_rng(), request, attendees),
     }
 }
 
@@ -31,33 +56,50 @@ pub fn draw(nb: i8, attendees: &Vec<Profile>) -> Result<Vec<&Profile>, Error> {
 mod tests {
     use super::*;
 
+    fn profile(first_name: &str, last_name: &str) -> Profile {
+        Profile { first_name: first_name.to_string(), last_name: last_name.to_string() }
+    }
+
+    fn request(nb: usize) -> DrawRequest {
+        DrawRequest { nb, ..DrawRequest::default() }
+    }
+
     #[test]
     fn test_draw() {
-        let attendees = vec![Profile { first_name: "Francois".to_string(), last_name: "Teychene".to_string() }];
-        let actual = draw(1, attendees.as_ref());
+        let attendees = vec![profile("Francois", "Teychene")];
+        let actual = draw(&request(1), attendees.as_ref());
         assert!(actual.is_ok());
-        assert_eq!(actual.unwrap().as_slice(), vec![&Profile { first_name: "Francois".to_string(), last_name: "Teychene".to_string() }].as_slice());
+        assert_eq!(actual.unwrap().as_slice(), vec![&profile("Francois", "Teychene")].as_slice());
 
-        let attendees = vec![Profile { first_name: "Francois".to_string(), last_name: "Teychene".to_string() }];
-        let actual = draw(40, attendees.as_ref());
+        let attendees = vec![profile("Francois", "Teychene")];
+        let actual = draw(&request(40), attendees.as_ref());
         assert!(actual.is_err());
         assert_eq!(actual.unwrap_err().downcast::<DrawError>().unwrap(), DrawError::NotEnoughtParticipant { asked: 40, existant: 1 });
 
-        let attendees = vec![Profile { first_name: "Francois".to_string(), last_name: "Teychene".to_string() }];
-        let actual = draw(-1, attendees.as_ref());
-        assert!(actual.is_err());
-        assert_eq!(actual.unwrap_err().downcast::<DrawError>().unwrap(), DrawError::InvalidDrawRequest {asked: -1});
-
-        let attendees = vec![Profile { first_name: "Francois".to_string(), last_name: "Teychene".to_string() }];
-        let actual = draw(-50, attendees.as_ref());
-        assert!(actual.is_err());
-        assert_eq!(actual.unwrap_err().downcast::<DrawError>().unwrap(), DrawError::InvalidDrawRequest {asked: -50});
-
-        let attendees = vec![Profile { first_name: "Francois".to_string(), last_name: "Teychene".to_string() }, Profile { first_name: "Fabien".to_string(), last_name: "Bernard".to_string() }];
-        let actual = draw(0, &attendees);
+        let attendees = vec![profile("Francois", "Teychene"), profile("Fabien", "Bernard")];
+        let actual = draw(&request(0), &attendees);
         assert!(actual.is_ok());
         let vec : Vec<&Profile> = Vec::new();
         assert_eq!(actual.unwrap().as_slice(), vec.as_slice());
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_draw_excluded() {
+        let attendees = vec![profile("Francois", "Teychene"), profile("Fabien", "Bernard")];
+        let excluded = DrawRequest { nb: 1, excluded: vec!["fabien BERNARD".to_string()], ..DrawRequest::default() };
+        assert_eq!(draw(&excluded, &attendees).unwrap(), vec![&attendees[0]]);
+
+        let excluded = DrawRequest { nb: 2, ..excluded };
+        assert_eq!(draw(&excluded, &attendees).unwrap_err().downcast::<DrawError>().unwrap(), DrawError::NotEnoughtParticipant { asked: 2, existant: 1 });
+    }
+
+    #[test]
+    fn test_draw_seed() {
+        let attendees: Vec<Profile> = (0..50).map(|index| profile("Attendee", &index.to_string())).collect();
+        let seeded = DrawRequest { nb: 5, seed: Some(42), ..DrawRequest::default() };
+        assert_eq!(draw(&seeded, &attendees).unwrap(), draw(&seeded, &attendees).unwrap());
+        let names: Vec<&str> = draw(&seeded, &attendees).unwrap().iter().map(|winner| winner.last_name.as_str()).collect();
+        assert_eq!(names, vec!["35", "44", "48", "40", "21"]);
+    }
+}
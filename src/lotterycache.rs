@@ -1,12 +1,100 @@
-use eventbrite::{Event, Profile, EventbriteError, load_attendees, get_current_event};
-use lottery::draw;
-use actix::{Actor, Context, Message, Handler, Addr};
+use eventbrite::{Event, Profile, EventbriteError, Attende, load_attendees, get_current_event, normalize_name, redact_token};
+use lottery::{draw, DrawRequest};
+use actix::{Actor, Arbiter, Context, Message, MessageResult, Handler, Addr};
 use actix::dev::{MessageResponse, ResponseChannel};
+use broadcast::{Broadcaster, LiveEvent, Publish};
+use chrono::{DateTime, Duration, Utc};
+use schemars::JsonSchema;
+use metrics::Metrics;
+use std::time::Instant;
 use LotteryError;
 
 pub struct LotteryCache {
-    attendees: Option<Vec<Profile>>,
+    tenant: String,
+    attendees: Option<Vec<Attende>>,
     event: Option<Event>,
+    broadcaster: Addr<Broadcaster>,
+    metrics: Metrics,
+    last_refresh: Option<DateTime<Utc>>,
+    last_refresh_error: Option<String>,
+    /// Start of the last refresh, a forced refresh requested before it reuses its outcome
+    last_refresh_started: Option<Instant>,
+    last_outcome: Option<RefreshOutcome>,
+    next_refresh: Option<DateTime<Utc>>,
+}
+
+impl LotteryCache {
+    pub fn new(tenant: String, broadcaster: Addr<Broadcaster>, metrics: Metrics) -> LotteryCache {
+        LotteryCache {
+            tenant, attendees: None, event: None, broadcaster, metrics,
+            last_refresh: None, last_refresh_error: None, last_refresh_started: None, last_outcome: None, next_refresh: None,
+        }
+    }
+
+    fn attendees_count(&self) -> usize {
+        self.attendees.as_ref().map_or(0, |attendees| attendees.len())
+    }
+
+    /// Replace the attendees, the screens are notified when their count changes
+    fn set_attendees(&mut self, attendees: Option<Vec<Attende>>) {
+        let previous_count = self.attendees_count();
+        self.attendees = attendees;
+        self.metrics.set_attendees(&self.tenant, self.attendees_count());
+        if self.attendees_count() != previous_count {
+            self.broadcaster.do_send(Publish(LiveEvent::AttendeesUpdated { count: self.attendees_count() }));
+        }
+    }
+
+    fn set_event(&mut self, event: Event) {
+        if self.event.as_ref() != Some(&event) {
+            self.broadcaster.do_send(Publish(LiveEvent::EventChanged { event: event.clone() }));
+        }
+        self.event = Some(event);
+    }
+
+    /// Apply the attendees loaded by a refresh. On errors the last attendees are kept so the draws go on,
+    /// the error makes the server not ready ; they are only cleared when eventbrite has no event anymore
+    fn apply_loaded(&mut self, loaded: Result<(Event, Vec<Attende>), failure::Error>) -> UpdateAttendeesResponse {
+        match loaded {
+            Ok((event, attendees)) => {
+                self.set_attendees(Some(attendees));
+                self.set_event(event);
+                UpdateAttendeesResponse::Updated
+            }
+            Err(e) => match e.downcast::<EventbriteError>() {
+                Ok(EventbriteError::NoEventAvailable) => {
+                    self.set_attendees(None);
+                    UpdateAttendeesResponse::NoEventAvailable
+                }
+                Ok(other_eventbrite_error) => UpdateAttendeesResponse::EventbriteError { error: other_eventbrite_error },
+                Err(error) => UpdateAttendeesResponse::UnexpectedError { error }
+            }
+        }
+    }
+
+    /// Load the attendees of the current event.
+    /// Returns the response of the refresh with its outcome, kept for the forced refreshes requested meanwhile
+    fn refresh(&mut self, organizer: &str, token: &str) -> (UpdateAttendeesResponse, RefreshOutcome) {
+        let start = Instant::now();
+        self.last_refresh_started = Some(start);
+        let loaded = get_current_event(organizer, token)
+            .and_then(|event| load_attendees(&event.id, token).map(|attendees| (event, attendees)));
+        let response = self.apply_loaded(loaded);
+        self.metrics.observe_refresh(&self.tenant, &response, start.elapsed());
+        let now = Utc::now();
+        self.last_refresh = Some(now);
+        self.last_refresh_error = response.error_message().map(|error| redact_token(&error, token));
+        let outcome = RefreshOutcome {
+            outcome: response.outcome().to_owned(),
+            error: self.last_refresh_error.clone(),
+            event_id: self.event.as_ref().map(|event| event.id.clone()),
+            attendees_count: self.attendees_count(),
+            refreshed_at: now,
+            deduplicated: false,
+        };
+        self.last_outcome = Some(outcome.clone());
+        (response, outcome)
+    }
 }
 
 //Messages
@@ -26,23 +114,143 @@ pub enum UpdateAttendeesResponse {
     },
 }
 
+impl UpdateAttendeesResponse {
+    /// Name of the variant, used as metrics label
+    pub fn outcome(&self) -> &'static str {
+        match *self {
+            UpdateAttendeesResponse::Updated => "updated",
+            UpdateAttendeesResponse::NoEventAvailable => "no_event_available",
+            UpdateAttendeesResponse::EventbriteError { .. } => "eventbrite_error",
+            UpdateAttendeesResponse::UnexpectedError { .. } => "unexpected_error",
+        }
+    }
+
+    /// Reason of a failed refresh
+    pub fn error_message(&self) -> Option<String> {
+        match *self {
+            UpdateAttendeesResponse::Updated => None,
+            UpdateAttendeesResponse::NoEventAvailable => Some("No event available on eventbrite".to_owned()),
+            UpdateAttendeesResponse::EventbriteError { ref error } => Some(format!("Error on eventbrite : {}", error)),
+            UpdateAttendeesResponse::UnexpectedError { ref error } => Some(format!("Unexpected error : {}", error)),
+        }
+    }
+}
+
+/// Refresh now, unless a refresh started since `requested_at` : its outcome is returned instead
+pub struct Refresh {
+    pub organizer: String,
+    pub token: String,
+    pub requested_at: Instant,
+}
+
+/// Time of the next refresh planned by the refresh loop
+pub struct ScheduleRefresh {
+    pub at: DateTime<Utc>,
+}
+
+/// Outcome of a refresh, answered to a forced refresh
+#[derive(Serialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct RefreshOutcome {
+    /// `updated`, `no_event_available`, `eventbrite_error` or `unexpected_error`
+    pub outcome: String,
+    pub error: Option<String>,
+    pub event_id: Option<String>,
+    pub attendees_count: usize,
+    pub refreshed_at: DateTime<Utc>,
+    /// The outcome of a refresh started after the request, which was not repeated
+    pub deduplicated: bool,
+}
+
+/// A refresh started at `last_started` loaded the changes made before `requested_at`
+fn covers(last_started: Option<Instant>, requested_at: Instant) -> bool {
+    last_started.is_some_and(|started| started >= requested_at)
+}
+
+/// Apply the attendees changed by an eventbrite webhook, loaded by the `WebhookLoader` ; the attendees of other events are ignored
+pub struct ApplyWebhook {
+    pub action: String,
+    /// `None` for the actions without attendees
+    pub changed: Option<Vec<Attende>>,
+}
+
+#[derive(Serialize, JsonSchema, Debug, PartialEq)]
+pub struct WebhookOutcome {
+    pub action: String,
+    /// Attendees of the current event added, updated or removed
+    pub applied: usize,
+    pub attendees_count: usize,
+}
+
+/// Add or replace the `changed` attendees of the event by id, the cancelled and refunded ones are removed
+fn merge_attendees(attendees: &mut Vec<Attende>, event_id: &str, changed: Vec<Attende>) -> usize {
+    let mut applied = 0;
+    for attendee in changed.into_iter().filter(|attendee| attendee.event_id == event_id) {
+        match (attendees.iter().position(|known| known.id == attendee.id), attendee.is_attending()) {
+            (Some(position), true) => attendees[position] = attendee,
+            (Some(position), false) => { attendees.remove(position); }
+            (None, true) => attendees.push(attendee),
+            (None, false) => continue,
+        }
+        applied += 1;
+    }
+    applied
+}
+
+/// Draw winners among the attendees
 pub struct GetAttendees {
-    pub nb: i8
+    pub request: DrawRequest
+}
+
+/// Winners of a draw and the event they were drawn from
+pub struct Drawn {
+    pub event_id: Option<String>,
+    pub winners: Vec<Profile>,
 }
 
 pub struct GetEvent {}
 
-// Actor impl
-impl Actor for LotteryCache {
-    type Context = Context<Self>;
+/// Check a person is among the attendees, names are compared without case
+pub struct FindAttendee {
+    pub first_name: String,
+    pub last_name: String,
+}
+
+/// Current state of the cache, shown on the presentation page
+pub struct GetSummary;
+
+#[derive(Serialize, JsonSchema, Debug, PartialEq)]
+pub struct Summary {
+    pub event: Option<Event>,
+    pub attendees_count: usize,
+}
+
+/// State of the refreshes from eventbrite
+pub struct GetCacheStatus;
+
+#[derive(Serialize, JsonSchema, Debug, PartialEq)]
+pub struct CacheStatus {
+    pub event_id: Option<String>,
+    pub attendees_count: usize,
+    pub last_refresh: Option<DateTime<Utc>>,
+    pub last_refresh_error: Option<String>,
+    /// Planned from the current event, the refreshes are less frequent far from it
+    pub next_refresh: Option<DateTime<Utc>>,
 }
 
-impl Default for LotteryCache {
-    fn default() -> Self {
-        LotteryCache { attendees: None, event: None }
+impl CacheStatus {
+    /// Attendees are loaded and were refreshed less than `max_age` ago, or the planned refresh is less than `max_age` late
+    pub fn is_ready(&self, now: DateTime<Utc>, max_age: Duration) -> bool {
+        self.last_refresh_error.is_none()
+            && self.last_refresh.is_some_and(|last_refresh| now.signed_duration_since(last_refresh) <= max_age
+                || self.next_refresh.is_some_and(|next_refresh| now.signed_duration_since(next_refresh) <= max_age))
     }
 }
 
+// Actor impl
+impl Actor for LotteryCache {
+    type Context = Context<Self>;
+}
+
 impl Message for UpdateAttendees {
     type Result = UpdateAttendeesResponse;
 }
@@ -64,40 +272,72 @@ impl Handler<UpdateAttendees> for LotteryCache {
     type Result = UpdateAttendeesResponse;
 
     fn handle(&mut self, msg: UpdateAttendees, _ctx: &mut Context<Self>) -> Self::Result {
-        let load_attendees = get_current_event(&msg.organizer, &msg.token)
-            .and_then(|event| load_attendees(&event.id, &msg.token).map(|attendees| (event, attendees)));
-        match load_attendees {
-            Ok((event, attendees)) => {
-                self.attendees = Some(attendees);
-                self.event = Some(event);
-                UpdateAttendeesResponse::Updated
+        self.refresh(&msg.organizer, &msg.token).0
+    }
+}
+
+impl Message for Refresh {
+    type Result = RefreshOutcome;
+}
+
+impl Handler<Refresh> for LotteryCache {
+    type Result = MessageResult<Refresh>;
+
+    fn handle(&mut self, msg: Refresh, _ctx: &mut Context<Self>) -> Self::Result {
+        if covers(self.last_refresh_started, msg.requested_at) {
+            if let Some(ref outcome) = self.last_outcome {
+                info!("[{}] Refresh already done since the request", self.tenant);
+                return MessageResult(RefreshOutcome { deduplicated: true, ..outcome.clone() });
             }
-            Err(e) => {
-                self.attendees = None;
-                match e.downcast::<EventbriteError>() {
-                    Ok(error) => match error {
-                        EventbriteError::NoEventAvailable => UpdateAttendeesResponse::NoEventAvailable,
-                        other_eventbrite_error => UpdateAttendeesResponse::EventbriteError { error: other_eventbrite_error }
-                    },
-                    Err(error) => UpdateAttendeesResponse::UnexpectedError { error: error }
-                }
+        }
+        MessageResult(self.refresh(&msg.organizer, &msg.token).1)
+    }
+}
+
+impl Message for ApplyWebhook {
+    type Result = WebhookOutcome;
+}
+
+impl Handler<ApplyWebhook> for LotteryCache {
+    type Result = MessageResult<ApplyWebhook>;
+
+    /// Before the first refresh the changes are left to it
+    fn handle(&mut self, msg: ApplyWebhook, _ctx: &mut Context<Self>) -> Self::Result {
+        let mut applied = 0;
+        if let (Some(changed), Some(event), Some(mut attendees)) = (msg.changed, self.event.clone(), self.attendees.clone()) {
+            applied = merge_attendees(&mut attendees, &event.id, changed);
+            if applied > 0 {
+                self.set_attendees(Some(attendees));
             }
         }
+        MessageResult(WebhookOutcome { action: msg.action, applied, attendees_count: self.attendees_count() })
     }
 }
 
 impl Message for GetAttendees {
-    type Result = Result<Vec<Profile>, LotteryError>;
+    type Result = Result<Drawn, LotteryError>;
 }
 
 impl Handler<GetAttendees> for LotteryCache {
-    type Result = Result<Vec<Profile>, LotteryError>;
+    type Result = Result<Drawn, LotteryError>;
 
     fn handle(&mut self, msg: GetAttendees, _ctx: &mut Context<Self>) -> Self::Result {
-        self.attendees.as_ref()
-            .ok_or(LotteryError::NoEventAvailable)
-            .and_then(|attendees| draw(msg.nb, attendees).map_err(|error| LotteryError::DrawError { cause: error }))
-            .map(|attendees| attendees.into_iter().map(|r| r.clone()).collect())
+        let attendees: Vec<Profile> = self.attendees.as_ref()
+            .ok_or(LotteryError::NoEventAvailable)?
+            .iter()
+            .map(|attendee| attendee.profile.clone())
+            .collect();
+        let winners: Vec<Profile> = draw(&msg.request, &attendees)
+            .map_err(|error| LotteryError::DrawError { cause: error })?
+            .into_iter()
+            .cloned()
+            .collect();
+
+        self.metrics.draw_performed(&self.tenant);
+        self.broadcaster.do_send(Publish(LiveEvent::Started { nb: winners.len(), prize: msg.request.prize }));
+        winners.iter().enumerate().for_each(|(index, winner)|
+            self.broadcaster.do_send(Publish(LiveEvent::WinnerRevealed { position: index + 1, winner: winner.clone() })));
+        Ok(Drawn { event_id: self.event.as_ref().map(|event| event.id.clone()), winners })
     }
 }
 
@@ -110,11 +350,177 @@ impl Handler<GetEvent> for LotteryCache {
 
     fn handle(&mut self, _msg: GetEvent, _ctx: &mut Context<Self>) -> Self::Result {
         self.event.as_ref()
+            .cloned()
             .ok_or(LotteryError::NoEventAvailable)
-            .map(|event| event.clone())
     }
 }
 
-pub fn start_cache() -> Addr<LotteryCache> {
-    LotteryCache::default().start()
-}
\ No newline at end of file
+impl Message for FindAttendee {
+    type Result = bool;
+}
+
+impl Handler<FindAttendee> for LotteryCache {
+    type Result = MessageResult<FindAttendee>;
+
+    fn handle(&mut self, msg: FindAttendee, _ctx: &mut Context<Self>) -> Self::Result {
+        let (first_name, last_name) = (normalize_name(&msg.first_name), normalize_name(&msg.last_name));
+        MessageResult(self.attendees.as_ref().is_some_and(|attendees| attendees.iter().any(|attendee|
+            normalize_name(&attendee.profile.first_name) == first_name && normalize_name(&attendee.profile.last_name) == last_name)))
+    }
+}
+
+impl Message for GetSummary {
+    type Result = Summary;
+}
+
+impl Handler<GetSummary> for LotteryCache {
+    type Result = MessageResult<GetSummary>;
+
+    fn handle(&mut self, _msg: GetSummary, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(Summary { event: self.event.clone(), attendees_count: self.attendees_count() })
+    }
+}
+
+impl Message for GetCacheStatus {
+    type Result = CacheStatus;
+}
+
+impl Handler<GetCacheStatus> for LotteryCache {
+    type Result = MessageResult<GetCacheStatus>;
+
+    fn handle(&mut self, _msg: GetCacheStatus, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(CacheStatus {
+            event_id: self.event.as_ref().map(|event| event.id.clone()),
+            attendees_count: self.attendees_count(),
+            last_refresh: self.last_refresh,
+            last_refresh_error: self.last_refresh_error.clone(),
+            next_refresh: self.next_refresh,
+        })
+    }
+}
+
+impl Message for ScheduleRefresh {
+    type Result = ();
+}
+
+impl Handler<ScheduleRefresh> for LotteryCache {
+    type Result = ();
+
+    fn handle(&mut self, msg: ScheduleRefresh, _ctx: &mut Context<Self>) {
+        self.next_refresh = Some(msg.at);
+    }
+}
+
+/// Start the cache of a tenant on its own arbiter, publishing its live events on `broadcaster`.
+/// The refreshes call eventbrite with a blocking client, they must not hold the event loop of the http server or of the other tenants
+pub fn start_cache(tenant: String, broadcaster: Addr<Broadcaster>, metrics: Metrics) -> Addr<LotteryCache> {
+    Arbiter::builder()
+        .name(format!("cache-{}", tenant))
+        .start(move |_| LotteryCache::new(tenant, broadcaster, metrics))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::System;
+    use broadcast::start_broadcaster;
+    use test_helpers::attendee;
+    use tokio::prelude::future;
+
+    #[test]
+    fn test_cache_status_is_ready() {
+        let now = Utc::now();
+        let status = |last_refresh: Option<DateTime<Utc>>, last_refresh_error: Option<&str>| CacheStatus {
+            event_id: Some("52097259305".to_string()),
+            attendees_count: 42,
+            last_refresh,
+            last_refresh_error: last_refresh_error.map(|error| error.to_string()),
+            next_refresh: None,
+        };
+        let max_age = Duration::seconds(60);
+
+        assert!(status(Some(now - Duration::seconds(10)), None).is_ready(now, max_age));
+        assert!(!status(Some(now - Duration::seconds(61)), None).is_ready(now, max_age));
+        assert!(!status(Some(now), Some("No event available on eventbrite")).is_ready(now, max_age));
+        assert!(!status(None, None).is_ready(now, max_age));
+
+        let idle = |next_refresh: DateTime<Utc>| CacheStatus { next_refresh: Some(next_refresh), ..status(Some(now - Duration::seconds(240)), None) };
+        assert!(idle(now + Duration::seconds(60)).is_ready(now, max_age));
+        assert!(!idle(now - Duration::seconds(61)).is_ready(now, max_age));
+    }
+
+    #[test]
+    fn test_apply_loaded_keeps_attendees_on_errors() {
+        let _system = System::new("test");
+        let mut cache = LotteryCache::new("bordeaux".to_string(), start_broadcaster(), Metrics::new());
+        let event = Event { id: "52097259305".to_string(), start: None, end: None };
+
+        let response = cache.apply_loaded(Ok((event.clone(), vec![attendee("1", "Ada", "Lovelace"), attendee("2", "Grace", "Lovelace")])));
+        assert!(matches!(response, UpdateAttendeesResponse::Updated));
+        assert_eq!(cache.attendees_count(), 2);
+
+        let response = cache.apply_loaded(Err(format_err!("Connection reset by peer")));
+        assert!(matches!(response, UpdateAttendeesResponse::UnexpectedError { .. }));
+        let response = cache.apply_loaded(Err(EventbriteError::AttendeesLoadError { event_id: event.id.clone(), cause: format_err!("Timeout") }.into()));
+        assert!(matches!(response, UpdateAttendeesResponse::EventbriteError { .. }));
+        assert_eq!(cache.attendees_count(), 2);
+        assert_eq!(cache.event, Some(event));
+
+        let response = cache.apply_loaded(Err(EventbriteError::NoEventAvailable.into()));
+        assert!(matches!(response, UpdateAttendeesResponse::NoEventAvailable));
+        assert_eq!(cache.attendees, None);
+    }
+
+    #[test]
+    fn test_merge_attendees() {
+        let mut attendees = vec![attendee("1", "Ada", "Lovelace"), attendee("2", "Grace", "Lovelace")];
+        let changed = vec![
+            attendee("1", "Augusta Ada", "Lovelace"),
+            Attende { cancelled: true, ..attendee("2", "Grace", "Lovelace") },
+            attendee("3", "Margaret", "Lovelace"),
+            Attende { cancelled: true, ..attendee("4", "Alan", "Lovelace") },
+            Attende { event_id: "51124390428".to_string(), ..attendee("5", "Linus", "Lovelace") },
+        ];
+        assert_eq!(merge_attendees(&mut attendees, "52097259305", changed), 3);
+        assert_eq!(attendees, vec![attendee("1", "Augusta Ada", "Lovelace"), attendee("3", "Margaret", "Lovelace")]);
+    }
+
+    #[test]
+    fn test_apply_webhook() {
+        let mut system = System::new("test");
+        let cache = system.block_on(future::lazy(|| {
+            let mut cache = LotteryCache::new("bordeaux".to_string(), start_broadcaster(), Metrics::new());
+            cache.apply_loaded(Ok((Event { id: "52097259305".to_string(), start: None, end: None }, vec![attendee("1", "Ada", "Lovelace")])));
+            Ok::<_, ()>(cache.start())
+        })).unwrap();
+
+        let outcome = system.block_on(cache.send(ApplyWebhook { action: "order.placed".to_string(), changed: Some(vec![attendee("2", "Grace", "Lovelace")]) })).unwrap();
+        assert_eq!(outcome, WebhookOutcome { action: "order.placed".to_string(), applied: 1, attendees_count: 2 });
+        let outcome = system.block_on(cache.send(ApplyWebhook { action: "test".to_string(), changed: None })).unwrap();
+        assert_eq!(outcome, WebhookOutcome { action: "test".to_string(), applied: 0, attendees_count: 2 });
+    }
+
+    #[test]
+    fn test_find_attendee() {
+        let mut system = System::new("test");
+        let cache = system.block_on(future::lazy(|| {
+            let mut cache = LotteryCache::new("bordeaux".to_string(), start_broadcaster(), Metrics::new());
+            let emile = attendee("1", "Émile ", "Zola");
+            cache.apply_loaded(Ok((Event { id: "52097259305".to_string(), start: None, end: None }, vec![emile])));
+            Ok::<_, ()>(cache.start())
+        })).unwrap();
+        let find = |first_name: &str, last_name: &str| FindAttendee { first_name: first_name.to_string(), last_name: last_name.to_string() };
+
+        assert!(system.block_on(cache.send(find("ÉMILE", " zola"))).unwrap());
+        assert!(!system.block_on(cache.send(find("Emile", "Zola"))).unwrap());
+    }
+
+    #[test]
+    fn test_covers() {
+        let requested_at = Instant::now();
+        assert!(!covers(None, requested_at));
+        assert!(!covers(Some(requested_at - ::std::time::Duration::from_secs(1)), requested_at));
+        assert!(covers(Some(requested_at), requested_at));
+        assert!(covers(Some(requested_at + ::std::time::Duration::from_millis(10)), requested_at));
+    }
+}
@@ -1,14 +1,22 @@
 #![allow(proc_macro_derive_resolution_fallback)] // Diesel compilation warning, should be fixed in diesel 1.4 TODO update diesel to 1.4 when released and remove this configuration
+#![allow(non_local_definitions)] // Diesel 1.x derives generate impls inside anonymous consts
 
 // Http client
+#[macro_use]
 extern crate failure;
 #[macro_use]
 extern crate failure_derive;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
+#[macro_use]
+extern crate serde_json;
+extern crate schemars;
+extern crate toml;
+extern crate clap;
 extern crate reqwest;
 extern crate rand;
+extern crate sha2;
 extern crate core;
 
 // Logger
@@ -20,6 +28,9 @@ extern crate env_logger;
 extern crate actix;
 extern crate actix_web;
 extern crate tokio;
+extern crate futures;
+extern crate bytes;
+extern crate prometheus;
 
 // Database
 #[macro_use]
@@ -27,6 +38,7 @@ extern crate diesel;
 #[macro_use]
 extern crate diesel_migrations;
 extern crate uuid;
+extern crate chrono;
 extern crate r2d2;
 
 #[cfg(test)]
@@ -40,21 +52,60 @@ mod lotterycache;
 mod cache_loop;
 mod schema;
 mod database;
+mod repository;
 mod basics;
+mod auth;
+mod broadcast;
+mod websocket;
+mod sse;
+mod metrics;
+mod api_error;
+mod openapi;
+mod tenant;
+mod config;
+mod cli;
+mod shutdown;
+mod reload;
+mod webhook;
+#[cfg(test)]
+mod test_helpers;
 
 use failure::Error;
 use std::env;
-use actix::{System, Arbiter, Addr};
-use tokio::prelude::Future;
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+use actix::{Actor, System, Arbiter};
+use config::{Config, SharedConfig};
 use web::WebState;
 
 
 #[derive(Fail, Debug)]
 pub enum LotteryError {
-    #[fail(display = "Invalid parameter")]
-    InvalidParameter,
+    #[fail(display = "Invalid parameter {}", name)]
+    InvalidParameter { name: String, reason: String },
+    #[fail(display = "Invalid request")]
+    InvalidRequest { details: String },
     #[fail(display = "No event available")]
     NoEventAvailable,
+    #[fail(display = "Database unavailable")]
+    DatabaseUnavailable { cause: Error },
+    #[fail(display = "Winner {} not found", id)]
+    WinnerNotFound { id: String },
+    #[fail(display = "Token {} not found", id)]
+    TokenNotFound { id: String },
+    #[fail(display = "Tenant {} not found", name)]
+    TenantNotFound { name: String },
+    #[fail(display = "Draw result {} not found", id)]
+    DrawResultNotFound { id: String },
+    #[fail(display = "Draw result {} is already recorded", id)]
+    AlreadyRecorded { id: String },
+    #[fail(display = "{} {} is neither an attendee nor drawn", first_name, last_name)]
+    UnknownAttendee { first_name: String, last_name: String },
+    #[fail(display = "{}", reason)]
+    NotAllowed { reason: String },
+    #[fail(display = "Invalid configuration")]
+    InvalidConfiguration { details: String },
     #[fail(display = "Error during attendees draw")]
     DrawError { cause: Error },
     #[fail(display = "Unexpected error")]
@@ -63,22 +114,90 @@ pub enum LotteryError {
 
 fn main() {
     env_logger::init();
-    let organizer = env::var("ORGANIZER_TOKEN").expect("ORGANIZER_TOKEN is mandatory");
-    let token = env::var("EVENTBRITE_TOKEN").expect("EVENTBRITE_TOKEN is mandatory");
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL env var is mandatory");
-    let http_bind = env::var("HTTP_BIND").unwrap_or("0.0.0.0".to_string());
-    let http_port = env::var("HTTP_PORT").unwrap_or("8088".to_string());
+    let matches = cli::app().get_matches();
+    let config_path = matches.value_of("config").map(Path::new);
+
+    let command = matches.subcommand();
+    if let (name, Some(args)) = command {
+        if name != "serve" {
+            let result = Config::read(config_path, |name| env::var(name).ok())
+                .map_err(|error| error.into())
+                .and_then(|config| cli::run(name, args, &config));
+            if let Err(error) = result {
+                eprintln!("{}", error);
+                error.iter_causes().for_each(|cause| eprintln!("Caused by : {}", cause));
+                process::exit(1)
+            }
+            return;
+        }
+    }
+
+    let config = match Config::load(config_path, |name| env::var(name).ok()) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1)
+        }
+    };
+    if matches.is_present("check-config") {
+        println!("Configuration is valid, {} tenant(s) : {}", config.tenants.len(),
+                 config.tenants.iter().map(|tenant| tenant.name.as_ref()).collect::<Vec<&str>>().join(", "));
+        return;
+    }
+    serve(config, config_path);
+}
+
+/// Start the tenants and the http server, until SIGINT or SIGTERM
+fn serve(config: Config, config_path: Option<&Path>) {
+    let api_keys = config.auth.api_keys().expect("Admin tokens are validated with the configuration");
+    if api_keys.is_empty() {
+        warn!("No admin tokens configured, write endpoints will reject every request");
+    }
 
     info!("Starting lottery");
     let system = System::new("lottery");
 
-    let db_addr = database::start_database(database_url);
-    let cache_addr = lotterycache::start_cache();
-
-    Arbiter::spawn(cache_loop::cache_update_interval(10, cache_addr.clone(), token, organizer));
-
-    web::http_server(WebState{cache: cache_addr, db: db_addr}, http_bind, http_port);
+    let db_addr = match database::start_database(config.database_config()) {
+        Ok(addr) => addr,
+        Err(error) => {
+            error!("{}", error);
+            error.iter_causes().for_each(|cause| error!("Caused by : {}", cause));
+            process::exit(1)
+        }
+    };
+    let metrics = metrics::Metrics::new();
+    let schedule = config.refresh_schedule();
+    let shutdown_flag = shutdown::ShutdownFlag::default();
+    let shared_config = SharedConfig::new(config.clone());
+    let tenants = tenant::Tenants::new(config.tenants.iter().map(|tenant_config| {
+        let name = tenant_config.name.clone();
+        info!("Starting tenant {}", name);
+        let broadcaster = broadcast::start_broadcaster();
+        let cache = lotterycache::start_cache(name.clone(), broadcaster.clone(), metrics.clone());
+        Arbiter::spawn(cache_loop::cache_update_loop(schedule.clone(), cache.clone(), name.clone(), shared_config.clone(), shutdown_flag.clone()));
+        tenant::Tenant { name, cache, broadcaster }
+    }).collect());
+
+    let state = WebState {
+        tenants,
+        db: db_addr.clone(),
+        api_keys,
+        cache_max_age: chrono::Duration::seconds(config.refresh.cache_max_age as i64),
+        metrics,
+        max_draw: config.limits.max_draw,
+        reloader: reload::Reloader { path: config_path.map(Path::to_path_buf), config: shared_config.clone() }.start(),
+        config: shared_config,
+        webhooks: webhook::start_webhook_loader(),
+    };
+    let server = web::http_server(state, config.http.bind.clone(), config.http.port.to_string(), config.http.cors_allowed_origins.clone(), config.shutdown.timeout);
+    shutdown::Shutdown {
+        server,
+        db: db_addr,
+        executors: config.database.executors,
+        timeout: Duration::from_secs(config.shutdown.timeout.into()),
+        flag: shutdown_flag,
+    }.start();
 
     system.run();
-
+    info!("Lottery stopped");
 }
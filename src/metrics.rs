@@ -0,0 +1,119 @@
+//! Prometheus metrics of the lottery, exposed on `/metrics`, labelled by tenant
+use actix_web::{HttpRequest, HttpResponse, Result};
+use actix_web::middleware::{Finished, Middleware, Started};
+use lotterycache::UpdateAttendeesResponse;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+use std::time::{Duration, Instant};
+use LotteryError;
+
+/// Metrics shared by the actors and the http server, clones update the same values
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    refreshes: IntCounterVec,
+    refresh_duration: HistogramVec,
+    attendees: IntGaugeVec,
+    draws: IntCounterVec,
+    recorded_winners: IntCounterVec,
+    http_requests: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let metrics = Metrics {
+            registry: Registry::new(),
+            refreshes: IntCounterVec::new(Opts::new("lottery_refreshes_total", "Refreshes of the attendees from eventbrite by outcome"), &["tenant", "outcome"])
+                .expect("Invalid metric"),
+            refresh_duration: HistogramVec::new(HistogramOpts::new("lottery_refresh_duration_seconds", "Duration of the refreshes from eventbrite"), &["tenant"])
+                .expect("Invalid metric"),
+            attendees: IntGaugeVec::new(Opts::new("lottery_attendees", "Attendees taking part in the draws"), &["tenant"])
+                .expect("Invalid metric"),
+            draws: IntCounterVec::new(Opts::new("lottery_draws_total", "Draws performed"), &["tenant"])
+                .expect("Invalid metric"),
+            recorded_winners: IntCounterVec::new(Opts::new("lottery_recorded_winners_total", "Winners recorded"), &["tenant"])
+                .expect("Invalid metric"),
+            http_requests: HistogramVec::new(HistogramOpts::new("lottery_http_request_duration_seconds", "Duration of the http requests by route"), &["route", "method", "status"])
+                .expect("Invalid metric"),
+        };
+        metrics.registry.register(Box::new(metrics.refreshes.clone())).expect("Metric registered twice");
+        metrics.registry.register(Box::new(metrics.refresh_duration.clone())).expect("Metric registered twice");
+        metrics.registry.register(Box::new(metrics.attendees.clone())).expect("Metric registered twice");
+        metrics.registry.register(Box::new(metrics.draws.clone())).expect("Metric registered twice");
+        metrics.registry.register(Box::new(metrics.recorded_winners.clone())).expect("Metric registered twice");
+        metrics.registry.register(Box::new(metrics.http_requests.clone())).expect("Metric registered twice");
+        metrics
+    }
+
+    pub fn observe_refresh(&self, tenant: &str, response: &UpdateAttendeesResponse, duration: Duration) {
+        self.refreshes.with_label_values(&[tenant, response.outcome()]).inc();
+        self.refresh_duration.with_label_values(&[tenant]).observe(duration.as_secs_f64());
+    }
+
+    pub fn set_attendees(&self, tenant: &str, count: usize) {
+        self.attendees.with_label_values(&[tenant]).set(count as i64);
+    }
+
+    pub fn draw_performed(&self, tenant: &str) {
+        self.draws.with_label_values(&[tenant]).inc();
+    }
+
+    pub fn winner_recorded(&self, tenant: &str) {
+        self.recorded_winners.with_label_values(&[tenant]).inc();
+    }
+
+    /// Metrics in the prometheus text format
+    pub fn render(&self) -> Result<String, LotteryError> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)
+            .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })?;
+        String::from_utf8(buffer)
+            .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
+    }
+}
+
+/// Start of a request, kept in the request extensions
+struct RequestStart(Instant);
+
+/// Middleware measuring the http requests, registered with the logger
+pub struct RequestMetrics(pub Metrics);
+
+impl<S> Middleware<S> for RequestMetrics {
+    fn start(&self, req: &HttpRequest<S>) -> Result<Started> {
+        req.extensions_mut().insert(RequestStart(Instant::now()));
+        Ok(Started::Done)
+    }
+
+    fn finish(&self, req: &HttpRequest<S>, resp: &HttpResponse) -> Finished {
+        if let Some(start) = req.extensions().get::<RequestStart>() {
+            // Patterns rather than paths, so ids don't create a serie per request
+            let route = req.resource().rdef().map_or("unmatched", |resource| resource.pattern());
+            self.0.http_requests
+                .with_label_values(&[route, req.method().as_str(), resp.status().as_str()])
+                .observe(start.0.elapsed().as_secs_f64());
+        }
+        Finished::Done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.observe_refresh("bordeaux", &UpdateAttendeesResponse::Updated, Duration::from_millis(250));
+        metrics.observe_refresh("toulouse", &UpdateAttendeesResponse::NoEventAvailable, Duration::from_millis(100));
+        metrics.set_attendees("bordeaux", 42);
+        metrics.clone().draw_performed("bordeaux");
+
+        let rendered = metrics.render().unwrap();
+        assert!(rendered.contains("lottery_refreshes_total{outcome=\"updated\",tenant=\"bordeaux\"} 1"));
+        assert!(rendered.contains("lottery_refreshes_total{outcome=\"no_event_available\",tenant=\"toulouse\"} 1"));
+        assert!(rendered.contains("lottery_refresh_duration_seconds_count{tenant=\"bordeaux\"} 1"));
+        assert!(rendered.contains("lottery_refresh_duration_seconds_count{tenant=\"toulouse\"} 1"));
+        assert!(rendered.contains("lottery_attendees{tenant=\"bordeaux\"} 42"));
+        assert!(rendered.contains("lottery_draws_total{tenant=\"bordeaux\"} 1"));
+        assert!(!rendered.contains("lottery_recorded_winners_total{"));
+    }
+}
@@ -0,0 +1,282 @@
+//! OpenAPI description of the api, the schemas are generated from the request and response types
+use api_error::ErrorBody;
+use auth::Role;
+use config::Reloaded;
+use database::{CreateToken, CreatedToken, UpdateWinner};
+use eventbrite::{Profile, WebhookPayload};
+use lotterycache::{RefreshOutcome, Summary, WebhookOutcome};
+use repository::{ApiToken, Draw, Winner};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde_json::{Map, Value};
+use tenant::DEFAULT_TENANT;
+use web::{RecordWinner, Status, WinnerQuery, API_PREFIX, TENANT_PREFIX};
+
+fn error_description(status: u16) -> &'static str {
+    match status {
+        400 => "Invalid request",
+        401 => "Missing or unknown token",
+        403 => "The role of the token is not enough, token of another tenant, or invalid webhook secret",
+        404 => "Not found, or unknown tenant",
+        409 => "Already recorded",
+        422 => "Neither an attendee nor drawn, or invalid configuration",
+        503 => "No event available or database unavailable",
+        _ => "Unexpected error",
+    }
+}
+
+fn response(description: &str, schema: Option<&Schema>) -> Value {
+    match schema {
+        Some(schema) => json!({ "description": description, "content": { "application/json": { "schema": schema } } }),
+        None => json!({ "description": description }),
+    }
+}
+
+/// Parameters of a query string, one per field of `T`
+fn query_parameters<T: JsonSchema>(gen: &mut SchemaGenerator) -> Vec<Value> {
+    let root = gen.root_schema_for::<T>();
+    root.schema.object.map_or_else(Vec::new, |object| object.properties.iter()
+        .map(|(name, schema)| json!({ "name": name, "in": "query", "required": object.required.contains(name), "schema": schema }))
+        .collect())
+}
+
+fn path_id(description: &str) -> Vec<Value> {
+    vec![json!({ "name": "id", "in": "path", "required": true, "description": description, "schema": { "type": "string" } })]
+}
+
+/// Operation answering `success`, `errors` or the errors of the authentication when a role is needed
+struct Operation<'a> {
+    summary: &'a str,
+    role: Option<Role>,
+    parameters: Vec<Value>,
+    body: Option<Schema>,
+    success: (u16, Value),
+    errors: &'a [u16],
+}
+
+impl<'a> Operation<'a> {
+    fn to_value(&self, error: &Schema) -> Value {
+        let mut responses = Map::new();
+        responses.insert(self.success.0.to_string(), self.success.1.clone());
+        let auth_errors: &[u16] = if self.role.is_some() { &[401, 403] } else { &[] };
+        auth_errors.iter().chain(self.errors.iter()).chain([500].iter())
+            .for_each(|status| { responses.insert(status.to_string(), response(error_description(*status), Some(error))); });
+
+        let mut operation = json!({ "summary": self.summary, "responses": responses });
+        if let Some(role) = self.role {
+            operation["description"] = json!(format!("Needs a `{}` token", role));
+            operation["security"] = json!([{ "bearer": [] }]);
+        }
+        if !self.parameters.is_empty() {
+            operation["parameters"] = json!(self.parameters);
+        }
+        if let Some(ref body) = self.body {
+            operation["requestBody"] = json!({ "required": true, "content": { "application/json": { "schema": body } } });
+        }
+        operation
+    }
+}
+
+/// OpenAPI 3 document of the api
+pub fn document() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let error = gen.subschema_for::<ErrorBody>();
+    let winner = gen.subschema_for::<Winner>();
+    let profiles = gen.subschema_for::<Vec<Profile>>();
+    let tokens = gen.subschema_for::<Vec<ApiToken>>();
+    let created_token = gen.subschema_for::<CreatedToken>();
+    let summary = gen.subschema_for::<Summary>();
+    let status = gen.subschema_for::<Status>();
+    let winner_parameters = query_parameters::<WinnerQuery>(&mut gen);
+
+    let draw = Operation {
+        summary: "Draw winners among the attendees of the current event",
+        role: Some(Role::Presenter),
+        parameters: winner_parameters,
+        body: None,
+        success: (200, response("Winners, in the order of the draw", Some(&profiles))),
+        errors: &[400, 503],
+    };
+    let create_draw = Operation {
+        summary: "Draw winners and keep the draw, its results can then be recorded. Same parameters as the draw of `/winners`",
+        role: Some(Role::Presenter),
+        parameters: vec![],
+        body: Some(gen.subschema_for::<WinnerQuery>()),
+        success: (201, response("Draw with its results", Some(&gen.subschema_for::<Draw>()))),
+        errors: &[400, 503],
+    };
+    let record = Operation {
+        summary: "Record a result of a draw, or for admins the name of a winner drawn outside of the api",
+        role: Some(Role::Presenter),
+        parameters: vec![],
+        body: Some(gen.subschema_for::<RecordWinner>()),
+        success: (200, response("Recorded winner", Some(&winner))),
+        errors: &[400, 404, 409, 422, 503],
+    };
+    let update_winner = Operation {
+        summary: "Fix the name of a recorded winner",
+        role: Some(Role::Admin),
+        parameters: path_id("Id of the winner"),
+        body: Some(gen.subschema_for::<UpdateWinner>()),
+        success: (200, response("Updated winner", Some(&winner))),
+        errors: &[400, 404, 503],
+    };
+    let delete_winner = Operation {
+        summary: "Delete a recorded winner, the change is kept in the audit trail",
+        role: Some(Role::Admin),
+        parameters: path_id("Id of the winner"),
+        body: None,
+        success: (204, response("Winner deleted", None)),
+        errors: &[404, 503],
+    };
+    let list_tokens = Operation {
+        summary: "List the tokens of the tenant which were not revoked",
+        role: Some(Role::Admin),
+        parameters: vec![],
+        body: None,
+        success: (200, response("Tokens, without their secret", Some(&tokens))),
+        errors: &[503],
+    };
+    let create_token = Operation {
+        summary: "Create a token only valid on the tenant, its secret is only returned in this response",
+        role: Some(Role::Admin),
+        parameters: vec![],
+        body: Some(gen.subschema_for::<CreateToken>()),
+        success: (201, response("Created token with its secret", Some(&created_token))),
+        errors: &[400, 503],
+    };
+    let revoke_token = Operation {
+        summary: "Revoke a token",
+        role: Some(Role::Admin),
+        parameters: path_id("Id of the token"),
+        body: None,
+        success: (204, response("Token revoked", None)),
+        errors: &[404, 503],
+    };
+    let eventbrite_webhook = Operation {
+        summary: "Eventbrite webhook, applies the attendees of `order.placed`, `attendee.updated` and `attendee.checked_in` loaded from the eventbrite api",
+        role: None,
+        parameters: vec![json!({ "name": "secret", "in": "query", "required": true, "description": "Webhook secret of the tenant", "schema": { "type": "string" } })],
+        body: Some(gen.subschema_for::<WebhookPayload>()),
+        success: (200, response("Attendees applied to the cache", Some(&gen.subschema_for::<WebhookOutcome>()))),
+        errors: &[400, 403],
+    };
+    let refresh = Operation {
+        summary: "Refresh the attendees from eventbrite now, a refresh started since the request is not repeated",
+        role: Some(Role::Admin),
+        parameters: vec![],
+        body: None,
+        success: (200, response("Outcome of the refresh", Some(&gen.subschema_for::<RefreshOutcome>()))),
+        errors: &[404],
+    };
+    let reload = Operation {
+        summary: "Reload the configuration file and the environment, new eventbrite credentials are used from the next refresh",
+        role: Some(Role::Admin),
+        parameters: vec![],
+        body: None,
+        success: (200, response("Changes applied, and the changes waiting for a restart", Some(&gen.subschema_for::<Reloaded>()))),
+        errors: &[422],
+    };
+    let get_summary = Operation {
+        summary: "Current event and attendees count",
+        role: None,
+        parameters: vec![],
+        body: None,
+        success: (200, response("Summary of the attendees cache", Some(&summary))),
+        errors: &[],
+    };
+    let get_status = Operation {
+        summary: "Database schema version and state of the refreshes from eventbrite",
+        role: None,
+        parameters: vec![],
+        body: None,
+        success: (200, response("Status of the service", Some(&status))),
+        errors: &[503],
+    };
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Jug Lottery",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [
+            { "url": API_PREFIX, "description": "Default tenant" },
+            {
+                "url": format!("{}{}", TENANT_PREFIX, API_PREFIX),
+                "description": "Named tenant, without the reload and this document",
+                "variables": { "tenant": { "default": DEFAULT_TENANT } },
+            },
+        ],
+        "paths": {
+            "/winners": { "get": draw.to_value(&error) },
+            "/draws": { "post": create_draw.to_value(&error) },
+            "/record": { "post": record.to_value(&error) },
+            "/record/{id}": { "put": update_winner.to_value(&error), "delete": delete_winner.to_value(&error) },
+            "/admin/tokens": { "get": list_tokens.to_value(&error), "post": create_token.to_value(&error) },
+            "/admin/tokens/{id}": { "delete": revoke_token.to_value(&error) },
+            "/webhooks/eventbrite": { "post": eventbrite_webhook.to_value(&error) },
+            "/admin/refresh": { "post": refresh.to_value(&error) },
+            "/admin/reload": { "post": reload.to_value(&error) },
+            "/summary": { "get": get_summary.to_value(&error) },
+            "/status": { "get": get_status.to_value(&error) },
+            "/events": { "get": {
+                "summary": "Live events as Server-Sent Events, replayed after `Last-Event-ID`",
+                "responses": { "200": { "description": "Stream of live events", "content": { "text/event-stream": { "schema": { "type": "string" } } } } },
+            } },
+            "/ws": { "get": {
+                "summary": "Live events as WebSocket text messages",
+                "responses": { "101": { "description": "Switching to the WebSocket protocol" } },
+            } },
+            "/openapi.json": { "get": {
+                "summary": "This document",
+                "responses": { "200": { "description": "OpenAPI document", "content": { "application/json": { "schema": { "type": "object" } } } } },
+            } },
+        },
+        "components": {
+            "securitySchemes": { "bearer": { "type": "http", "scheme": "bearer" } },
+            "schemas": gen.take_definitions(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// References found in a json value
+    fn references(value: &Value, found: &mut Vec<String>) {
+        match *value {
+            Value::Object(ref map) => map.iter().for_each(|(key, value)| match *value {
+                Value::String(ref reference) if key == "$ref" => found.push(reference.clone()),
+                _ => references(value, found),
+            }),
+            Value::Array(ref values) => values.iter().for_each(|value| references(value, found)),
+            _ => (),
+        }
+    }
+
+    #[test]
+    fn test_document() {
+        let document = document();
+        let schemas = document["components"]["schemas"].as_object().unwrap();
+        ["WinnerQuery", "Draw", "DrawResult", "RecordWinner", "CreateWinner", "UpdateWinner", "Winner", "Profile", "ErrorBody", "CreateToken", "CreatedToken", "ApiToken", "Role", "Summary", "Status"].iter()
+            .for_each(|name| assert!(schemas.contains_key(*name), "Missing schema {}", name));
+
+        let mut found = Vec::new();
+        references(&document, &mut found);
+        assert!(!found.is_empty());
+        found.iter().for_each(|reference| {
+            let name = reference.trim_start_matches("#/components/schemas/");
+            assert!(schemas.contains_key(name), "Unresolved reference {}", reference);
+        });
+
+        let draw = &document["paths"]["/winners"]["get"];
+        let nb = draw["parameters"].as_array().unwrap().iter().find(|parameter| parameter["name"] == "nb").unwrap();
+        assert_eq!(nb["required"], true);
+        assert_eq!(draw["parameters"].as_array().unwrap().len(), 4);
+        assert!(draw["responses"]["401"].is_object());
+        assert!(schemas["UpdateWinner"]["properties"].get("author").is_none());
+    }
+}
@@ -0,0 +1,62 @@
+//! Reload of the configuration on SIGHUP or `POST /admin/reload` : new eventbrite credentials are used from the next refresh
+use actix::actors::signal::{ProcessSignals, Signal, SignalType, Subscribe};
+use actix::prelude::*;
+use config::{Config, ConfigError, Reloaded, SharedConfig};
+use std::env;
+use std::path::PathBuf;
+
+pub struct Reloader {
+    /// Configuration file given at startup, the environment is read again too
+    pub path: Option<PathBuf>,
+    pub config: SharedConfig,
+}
+
+/// Read and validate the configuration, then apply it
+pub struct Reload;
+
+impl Message for Reload {
+    type Result = Result<Reloaded, ConfigError>;
+}
+
+impl Reloader {
+    fn reload(&self) -> Result<Reloaded, ConfigError> {
+        let reloaded = Config::load(self.path.as_deref(), |name| env::var(name).ok())
+            .and_then(|config| self.config.reload(config));
+        match reloaded {
+            Ok(ref reloaded) => {
+                info!("Configuration reloaded, new credentials for the tenants [{}]", reloaded.updated_tenants.join(", "));
+                if !reloaded.restart_required.is_empty() {
+                    warn!("Changes of the sections [{}] are applied on the next restart", reloaded.restart_required.join(", "));
+                }
+            }
+            Err(ref error) => error!("Configuration not reloaded : {}", error),
+        }
+        reloaded
+    }
+}
+
+impl Actor for Reloader {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        System::current().registry().get::<ProcessSignals>().do_send(Subscribe(ctx.address().recipient()));
+    }
+}
+
+impl Handler<Signal> for Reloader {
+    type Result = ();
+
+    fn handle(&mut self, msg: Signal, _: &mut Self::Context) {
+        if let SignalType::Hup = msg.0 {
+            let _ = self.reload();
+        }
+    }
+}
+
+impl Handler<Reload> for Reloader {
+    type Result = Result<Reloaded, ConfigError>;
+
+    fn handle(&mut self, _msg: Reload, _: &mut Self::Context) -> Self::Result {
+        self.reload()
+    }
+}
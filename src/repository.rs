@@ -0,0 +1,548 @@
+//! Persistence of winners and tokens, every write runs in its own transaction
+use auth::Role;
+use chrono::{NaiveDateTime, Utc};
+use diesel;
+use diesel::prelude::*;
+use rand::{thread_rng, Rng};
+use schemars::JsonSchema;
+use sha2::{Digest, Sha256};
+use uuid;
+use database::DbConnection;
+use eventbrite::{normalize_name, Profile};
+use schema::{api_tokens, draw_results, draws, winners, winner_audits};
+use LotteryError;
+
+/// Columns loaded into a `Winner`, the soft delete flag is only used for filtering
+const WINNER_COLUMNS: (winners::id, winners::first_name, winners::last_name, winners::event_id) =
+    (winners::id, winners::first_name, winners::last_name, winners::event_id);
+
+#[derive(Serialize, Queryable, JsonSchema, Debug, Clone, PartialEq)]
+pub struct Winner {
+    pub id: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub event_id: String
+}
+
+#[derive(Insertable)]
+#[table_name = "winners"]
+pub struct NewWinner<'a> {
+    pub id: &'a str,
+    pub first_name: &'a str,
+    pub last_name: &'a str,
+    pub event_id: &'a str,
+    pub tenant: &'a str,
+}
+
+/// Trace of a change made on a recorded winner
+#[derive(Insertable)]
+#[table_name = "winner_audits"]
+struct NewWinnerAudit<'a> {
+    id: &'a str,
+    winner_id: &'a str,
+    action: &'a str,
+    author: &'a str,
+    previous_first_name: Option<&'a str>,
+    previous_last_name: Option<&'a str>,
+    first_name: Option<&'a str>,
+    last_name: Option<&'a str>,
+    changed_at: NaiveDateTime,
+}
+
+fn new_id() -> String {
+    format!("{}", uuid::Uuid::new_v4())
+}
+
+/// Insert a winner and build the result from the inserted values, no reload needed
+pub fn insert_winner(conn: &DbConnection, winner: &NewWinner) -> Result<Winner, LotteryError> {
+    conn.transaction(|| {
+        diesel::insert_into(winners::table)
+            .values(winner)
+            .execute(conn)?;
+
+        Ok(Winner {
+            id: winner.id.to_owned(),
+            first_name: winner.first_name.to_owned(),
+            last_name: winner.last_name.to_owned(),
+            event_id: winner.event_id.to_owned(),
+        })
+    })
+}
+
+/// Load a winner of the tenant which was not deleted
+pub fn find_winner(conn: &DbConnection, tenant: &str, winner_id: &str) -> Result<Winner, LotteryError> {
+    winners::table
+        .find(winner_id)
+        .select(WINNER_COLUMNS)
+        .filter(winners::deleted.eq(false))
+        .filter(winners::tenant.eq(tenant))
+        .first::<Winner>(conn)
+        .optional()?
+        .ok_or_else(|| LotteryError::WinnerNotFound { id: winner_id.to_owned() })
+}
+
+/// Winners of the tenant which were not deleted, of a single event when given
+pub fn list_winners(conn: &DbConnection, tenant: &str, event_id: Option<&str>) -> Result<Vec<Winner>, LotteryError> {
+    let mut query = winners::table
+        .select(WINNER_COLUMNS)
+        .filter(winners::deleted.eq(false))
+        .filter(winners::tenant.eq(tenant))
+        .order((winners::event_id, winners::last_name, winners::first_name))
+        .into_boxed();
+    if let Some(event_id) = event_id {
+        query = query.filter(winners::event_id.eq(event_id));
+    }
+    Ok(query.load::<Winner>(conn)?)
+}
+
+pub fn update_winner(conn: &DbConnection, tenant: &str, winner_id: &str, first_name: &str, last_name: &str, author: &str) -> Result<Winner, LotteryError> {
+    conn.transaction(|| {
+        let previous = find_winner(conn, tenant, winner_id)?;
+
+        diesel::update(winners::table.find(winner_id))
+            .set((winners::first_name.eq(first_name), winners::last_name.eq(last_name)))
+            .execute(conn)?;
+
+        insert_audit(conn, &NewWinnerAudit {
+            id: &new_id(),
+            winner_id,
+            action: "update",
+            author,
+            previous_first_name: Some(&previous.first_name),
+            previous_last_name: Some(&previous.last_name),
+            first_name: Some(first_name),
+            last_name: Some(last_name),
+            changed_at: Utc::now().naive_utc(),
+        })?;
+
+        Ok(Winner { first_name: first_name.to_owned(), last_name: last_name.to_owned(), ..previous })
+    })
+}
+
+/// Soft delete a winner, the row is kept for the audit trail
+pub fn delete_winner(conn: &DbConnection, tenant: &str, winner_id: &str, author: &str) -> Result<Winner, LotteryError> {
+    conn.transaction(|| {
+        let previous = find_winner(conn, tenant, winner_id)?;
+
+        diesel::update(winners::table.find(winner_id))
+            .set(winners::deleted.eq(true))
+            .execute(conn)?;
+
+        insert_audit(conn, &NewWinnerAudit {
+            id: &new_id(),
+            winner_id,
+            action: "delete",
+            author,
+            previous_first_name: Some(&previous.first_name),
+            previous_last_name: Some(&previous.last_name),
+            first_name: None,
+            last_name: None,
+            changed_at: Utc::now().naive_utc(),
+        })?;
+
+        Ok(previous)
+    })
+}
+
+fn insert_audit(conn: &DbConnection, audit: &NewWinnerAudit) -> Result<(), LotteryError> {
+    diesel::insert_into(winner_audits::table)
+        .values(audit)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Winners drawn together, kept so only drawn attendees can be recorded
+#[derive(Serialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct Draw {
+    pub id: String,
+    pub event_id: String,
+    pub author: String,
+    pub seed: Option<u64>,
+    pub prize: Option<String>,
+    pub drawn_at: NaiveDateTime,
+    pub results: Vec<DrawResult>,
+}
+
+#[derive(Serialize, Queryable, JsonSchema, Debug, Clone, PartialEq)]
+pub struct DrawResult {
+    pub id: String,
+    /// Position in the draw, starting at 1
+    pub position: i32,
+    pub first_name: String,
+    pub last_name: String,
+    /// Recorded winner, once the result was recorded
+    pub winner_id: Option<String>,
+}
+
+const DRAW_RESULT_COLUMNS: (draw_results::id, draw_results::position, draw_results::first_name, draw_results::last_name, draw_results::winner_id) =
+    (draw_results::id, draw_results::position, draw_results::first_name, draw_results::last_name, draw_results::winner_id);
+
+#[derive(Insertable)]
+#[table_name = "draws"]
+pub struct NewDraw<'a> {
+    pub id: &'a str,
+    pub event_id: &'a str,
+    pub author: &'a str,
+    /// Seeds are unsigned, stored with the same bits in a signed column
+    pub seed: Option<i64>,
+    pub prize: Option<&'a str>,
+    pub drawn_at: NaiveDateTime,
+    pub tenant: &'a str,
+}
+
+#[derive(Insertable)]
+#[table_name = "draw_results"]
+struct NewDrawResult<'a> {
+    id: &'a str,
+    draw_id: &'a str,
+    position: i32,
+    first_name: &'a str,
+    last_name: &'a str,
+}
+
+/// Insert a draw with its winners, in the order of the draw
+pub fn insert_draw(conn: &DbConnection, draw: &NewDraw, winners: &[Profile]) -> Result<Draw, LotteryError> {
+    conn.transaction(|| {
+        diesel::insert_into(draws::table)
+            .values(draw)
+            .execute(conn)?;
+
+        let results = winners.iter().enumerate()
+            .map(|(index, winner)| DrawResult {
+                id: new_id(),
+                position: index as i32 + 1,
+                first_name: winner.first_name.clone(),
+                last_name: winner.last_name.clone(),
+                winner_id: None,
+            })
+            .collect::<Vec<_>>();
+        let new_results = results.iter()
+            .map(|result| NewDrawResult {
+                id: &result.id,
+                draw_id: draw.id,
+                position: result.position,
+                first_name: &result.first_name,
+                last_name: &result.last_name,
+            })
+            .collect::<Vec<_>>();
+        diesel::insert_into(draw_results::table)
+            .values(&new_results)
+            .execute(conn)?;
+
+        Ok(Draw {
+            id: draw.id.to_owned(),
+            event_id: draw.event_id.to_owned(),
+            author: draw.author.to_owned(),
+            seed: draw.seed.map(|seed| seed as u64),
+            prize: draw.prize.map(|prize| prize.to_owned()),
+            drawn_at: draw.drawn_at,
+            results,
+        })
+    })
+}
+
+/// Check a person was drawn by the tenant, during the given event when it is known.
+/// Names are compared in rust with `normalize_name`, like the attendees : the case folding of the databases differs
+pub fn is_drawn(conn: &DbConnection, tenant: &str, event_id: Option<&str>, first_name: &str, last_name: &str) -> Result<bool, LotteryError> {
+    let mut query = draw_results::table
+        .inner_join(draws::table)
+        .select((draw_results::first_name, draw_results::last_name))
+        .filter(draws::tenant.eq(tenant))
+        .into_boxed();
+    if let Some(event_id) = event_id {
+        query = query.filter(draws::event_id.eq(event_id));
+    }
+    let (first_name, last_name) = (normalize_name(first_name), normalize_name(last_name));
+    Ok(query.load::<(String, String)>(conn)?.iter()
+        .any(|(drawn_first_name, drawn_last_name)| normalize_name(drawn_first_name) == first_name && normalize_name(drawn_last_name) == last_name))
+}
+
+/// Record the winner of a draw result, a result can only be recorded once.
+/// The result is claimed by an update conditioned on its missing winner, so among concurrent records only one
+/// claims it and the winners inserted by the others are rolled back
+pub fn record_draw_result(conn: &DbConnection, tenant: &str, result_id: &str) -> Result<Winner, LotteryError> {
+    conn.transaction(|| {
+        let (result, event_id) = draw_results::table
+            .inner_join(draws::table)
+            .filter(draw_results::id.eq(result_id))
+            .filter(draws::tenant.eq(tenant))
+            .select((DRAW_RESULT_COLUMNS, draws::event_id))
+            .first::<(DrawResult, String)>(conn)
+            .optional()?
+            .ok_or_else(|| LotteryError::DrawResultNotFound { id: result_id.to_owned() })?;
+
+        let winner = insert_winner(conn, &NewWinner {
+            id: &new_id(),
+            first_name: &result.first_name,
+            last_name: &result.last_name,
+            event_id: &event_id,
+            tenant,
+        })?;
+        let claimed = diesel::update(draw_results::table.find(result_id).filter(draw_results::winner_id.is_null()))
+            .set(draw_results::winner_id.eq(&winner.id))
+            .execute(conn)?;
+        if claimed != 1 {
+            return Err(LotteryError::AlreadyRecorded { id: result_id.to_owned() });
+        }
+        Ok(winner)
+    })
+}
+
+/// Token stored in database, the secret itself is only known at creation
+#[derive(Serialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    pub role: Role,
+    /// Only tenant the token gives access to
+    pub tenant: String,
+    pub created_at: NaiveDateTime,
+}
+
+const API_TOKEN_COLUMNS: (api_tokens::id, api_tokens::name, api_tokens::role, api_tokens::tenant, api_tokens::created_at) =
+    (api_tokens::id, api_tokens::name, api_tokens::role, api_tokens::tenant, api_tokens::created_at);
+
+type ApiTokenRow = (String, String, String, String, NaiveDateTime);
+
+fn to_api_token((id, name, role, tenant, created_at): ApiTokenRow) -> Result<ApiToken, LotteryError> {
+    role.parse()
+        .map(|role| ApiToken { id, name, role, tenant, created_at })
+        .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
+}
+
+#[derive(Insertable)]
+#[table_name = "api_tokens"]
+struct NewApiToken<'a> {
+    id: &'a str,
+    name: &'a str,
+    token_hash: &'a str,
+    role: &'a str,
+    tenant: &'a str,
+    created_at: NaiveDateTime,
+}
+
+/// Only a hash of the secrets is stored
+fn hash_token(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+fn generate_secret() -> String {
+    let bytes: [u8; 32] = thread_rng().gen();
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Create a token of the tenant and return it with its secret
+pub fn create_token(conn: &DbConnection, tenant: &str, name: &str, role: Role) -> Result<(ApiToken, String), LotteryError> {
+    let secret = generate_secret();
+    let token = ApiToken { id: new_id(), name: name.to_owned(), role, tenant: tenant.to_owned(), created_at: Utc::now().naive_utc() };
+    conn.transaction::<_, LotteryError, _>(|| {
+        diesel::insert_into(api_tokens::table)
+            .values(&NewApiToken {
+                id: &token.id,
+                name: &token.name,
+                token_hash: &hash_token(&secret),
+                role: role.as_str(),
+                tenant: &token.tenant,
+                created_at: token.created_at,
+            })
+            .execute(conn)?;
+        Ok(())
+    })?;
+    Ok((token, secret))
+}
+
+/// Tokens of the tenant which were not revoked
+pub fn list_tokens(conn: &DbConnection, tenant: &str) -> Result<Vec<ApiToken>, LotteryError> {
+    api_tokens::table
+        .select(API_TOKEN_COLUMNS)
+        .filter(api_tokens::tenant.eq(tenant))
+        .filter(api_tokens::revoked.eq(false))
+        .order(api_tokens::created_at)
+        .load::<ApiTokenRow>(conn)?
+        .into_iter()
+        .map(to_api_token)
+        .collect()
+}
+
+/// Find the valid token matching a secret
+pub fn find_token(conn: &DbConnection, secret: &str) -> Result<Option<ApiToken>, LotteryError> {
+    api_tokens::table
+        .select(API_TOKEN_COLUMNS)
+        .filter(api_tokens::token_hash.eq(hash_token(secret)))
+        .filter(api_tokens::revoked.eq(false))
+        .first::<ApiTokenRow>(conn)
+        .optional()?
+        .map(to_api_token)
+        .transpose()
+}
+
+pub fn revoke_token(conn: &DbConnection, tenant: &str, token_id: &str) -> Result<ApiToken, LotteryError> {
+    conn.transaction(|| {
+        let token = api_tokens::table
+            .find(token_id)
+            .select(API_TOKEN_COLUMNS)
+            .filter(api_tokens::tenant.eq(tenant))
+            .filter(api_tokens::revoked.eq(false))
+            .first::<ApiTokenRow>(conn)
+            .optional()?
+            .ok_or_else(|| LotteryError::TokenNotFound { id: token_id.to_owned() })
+            .and_then(to_api_token)?;
+
+        diesel::update(api_tokens::table.find(token_id))
+            .set(api_tokens::revoked.eq(true))
+            .execute(conn)?;
+
+        Ok(token)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::tests::test_connection;
+    use diesel::result::{DatabaseErrorKind, Error};
+
+    fn new_winner<'a>(id: &'a str) -> NewWinner<'a> {
+        NewWinner { id, first_name: "Francois", last_name: "Teychene", event_id: "51124390428", tenant: "bordeaux" }
+    }
+
+    fn audit_actions(conn: &DbConnection, winner_id: &str) -> Vec<(String, String)> {
+        winner_audits::table
+            .select((winner_audits::action, winner_audits::author))
+            .filter(winner_audits::winner_id.eq(winner_id))
+            .order(winner_audits::changed_at)
+            .load(conn)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_insert_winner() {
+        let conn = test_connection();
+
+        let actual = insert_winner(&conn, &new_winner("1"));
+        let expected = Winner { id: "1".to_string(), first_name: "Francois".to_string(), last_name: "Teychene".to_string(), event_id: "51124390428".to_string() };
+        assert_eq!(actual.unwrap(), expected);
+        assert_eq!(find_winner(&conn, "bordeaux", "1").unwrap(), expected);
+    }
+
+    #[test]
+    fn test_insert_winner_duplicated_id() {
+        let conn = test_connection();
+
+        assert!(insert_winner(&conn, &new_winner("1")).is_ok());
+        match insert_winner(&conn, &new_winner("1")) {
+            Err(LotteryError::UnexpectedError { cause }) => assert!(matches!(cause.downcast::<Error>(), Ok(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)))),
+            other => panic!("Unexpected result {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_find_winner() {
+        let conn = test_connection();
+        insert_winner(&conn, &new_winner("1")).unwrap();
+
+        assert!(find_winner(&conn, "bordeaux", "1").is_ok());
+        assert!(matches!(find_winner(&conn, "bordeaux", "2"), Err(LotteryError::WinnerNotFound { ref id }) if id == "2"));
+        assert!(matches!(find_winner(&conn, "toulouse", "1"), Err(LotteryError::WinnerNotFound { .. })));
+    }
+
+    #[test]
+    fn test_list_winners() {
+        let conn = test_connection();
+        insert_winner(&conn, &new_winner("1")).unwrap();
+        insert_winner(&conn, &NewWinner { id: "2", first_name: "Fabien", last_name: "Bernard", event_id: "51124390428", tenant: "bordeaux" }).unwrap();
+        insert_winner(&conn, &NewWinner { event_id: "52097259305", ..new_winner("3") }).unwrap();
+        insert_winner(&conn, &NewWinner { tenant: "toulouse", ..new_winner("4") }).unwrap();
+        delete_winner(&conn, "bordeaux", "3", "admin").unwrap();
+
+        let ids = |winners: Vec<Winner>| winners.into_iter().map(|winner| winner.id).collect::<Vec<_>>();
+        assert_eq!(ids(list_winners(&conn, "bordeaux", None).unwrap()), vec!["2", "1"]);
+        assert_eq!(ids(list_winners(&conn, "bordeaux", Some("52097259305")).unwrap()), Vec::<String>::new());
+        assert_eq!(ids(list_winners(&conn, "toulouse", None).unwrap()), vec!["4"]);
+    }
+
+    #[test]
+    fn test_update_winner() {
+        let conn = test_connection();
+        insert_winner(&conn, &new_winner("1")).unwrap();
+
+        let actual = update_winner(&conn, "bordeaux", "1", "Fabien", "Bernard", "admin").unwrap();
+        assert_eq!((actual.first_name.as_ref(), actual.last_name.as_ref()), ("Fabien", "Bernard"));
+        assert_eq!(find_winner(&conn, "bordeaux", "1").unwrap(), actual);
+        assert_eq!(audit_actions(&conn, "1"), vec![("update".to_string(), "admin".to_string())]);
+
+        assert!(matches!(update_winner(&conn, "bordeaux", "2", "Fabien", "Bernard", "admin"), Err(LotteryError::WinnerNotFound { .. })));
+    }
+
+    #[test]
+    fn test_delete_winner() {
+        let conn = test_connection();
+        insert_winner(&conn, &new_winner("1")).unwrap();
+
+        assert!(delete_winner(&conn, "bordeaux", "1", "admin").is_ok());
+        assert!(matches!(find_winner(&conn, "bordeaux", "1"), Err(LotteryError::WinnerNotFound { .. })));
+        assert!(matches!(delete_winner(&conn, "bordeaux", "1", "admin"), Err(LotteryError::WinnerNotFound { .. })));
+        assert!(matches!(update_winner(&conn, "bordeaux", "1", "Fabien", "Bernard", "admin"), Err(LotteryError::WinnerNotFound { .. })));
+        assert_eq!(audit_actions(&conn, "1"), vec![("delete".to_string(), "admin".to_string())]);
+    }
+
+    #[test]
+    fn test_draw() {
+        let conn = test_connection();
+        let winners = vec![
+            Profile { first_name: "Francois".to_string(), last_name: "Teychene".to_string() },
+            Profile { first_name: "Fabien".to_string(), last_name: "Bernard".to_string() },
+        ];
+
+        let draw = insert_draw(&conn, &NewDraw {
+            id: "1",
+            event_id: "51124390428",
+            author: "fabien",
+            seed: Some(u64::MAX as i64),
+            prize: Some("IntelliJ licence"),
+            drawn_at: Utc::now().naive_utc(),
+            tenant: "bordeaux",
+        }, &winners).unwrap();
+        assert_eq!(draw.seed, Some(u64::MAX));
+        assert_eq!(draw.results.iter().map(|result| (result.position, result.first_name.as_ref())).collect::<Vec<_>>(), vec![(1, "Francois"), (2, "Fabien")]);
+
+        let winner = record_draw_result(&conn, "bordeaux", &draw.results[1].id).unwrap();
+        assert_eq!((winner.first_name.as_ref(), winner.event_id.as_ref()), ("Fabien", "51124390428"));
+        assert_eq!(find_winner(&conn, "bordeaux", &winner.id).unwrap(), winner);
+        assert!(matches!(record_draw_result(&conn, "bordeaux", &draw.results[1].id), Err(LotteryError::AlreadyRecorded { .. })));
+        assert_eq!(winners::table.filter(winners::first_name.eq("Fabien")).count().get_result::<i64>(&conn).unwrap(), 1);
+        assert!(matches!(record_draw_result(&conn, "bordeaux", "2"), Err(LotteryError::DrawResultNotFound { .. })));
+        assert!(matches!(record_draw_result(&conn, "toulouse", &draw.results[0].id), Err(LotteryError::DrawResultNotFound { .. })));
+
+        assert!(is_drawn(&conn, "bordeaux", Some("51124390428"), "francois ", "TEYCHENE").unwrap());
+        assert!(is_drawn(&conn, "bordeaux", None, "Francois", "Teychene").unwrap());
+        assert!(!is_drawn(&conn, "bordeaux", Some("52097259305"), "Francois", "Teychene").unwrap());
+        assert!(!is_drawn(&conn, "bordeaux", Some("51124390428"), "Jean-Luc", "Racine").unwrap());
+        assert!(!is_drawn(&conn, "toulouse", None, "Francois", "Teychene").unwrap());
+
+        // Stored names are compared like the attendees, with their spaces trimmed and unicode lowercase on every backend
+        let new_draw = NewDraw { id: "2", event_id: "51124390428", author: "fabien", seed: None, prize: None, drawn_at: Utc::now().naive_utc(), tenant: "bordeaux" };
+        insert_draw(&conn, &new_draw, &[Profile { first_name: "Émile ".to_string(), last_name: "Zola".to_string() }]).unwrap();
+        assert!(is_drawn(&conn, "bordeaux", Some("51124390428"), "ÉMILE", " zola").unwrap());
+    }
+
+    #[test]
+    fn test_tokens() {
+        let conn = test_connection();
+
+        let (presenter, secret) = create_token(&conn, "bordeaux", "fabien", Role::Presenter).unwrap();
+        let (admin, _) = create_token(&conn, "bordeaux", "francois", Role::Admin).unwrap();
+        let (other, _) = create_token(&conn, "toulouse", "francois", Role::Admin).unwrap();
+        assert_eq!(secret.len(), 64);
+        assert_eq!(find_token(&conn, &secret).unwrap(), Some(presenter.clone()));
+        assert_eq!(find_token(&conn, "5O5ICDI5I4LUFCAZRSTX").unwrap(), None);
+        assert_eq!(list_tokens(&conn, "bordeaux").unwrap().len(), 2);
+        assert_eq!(list_tokens(&conn, "toulouse").unwrap(), vec![other.clone()]);
+
+        assert!(matches!(revoke_token(&conn, "bordeaux", &other.id), Err(LotteryError::TokenNotFound { .. })));
+        assert_eq!(revoke_token(&conn, "bordeaux", &presenter.id).unwrap(), presenter);
+        assert_eq!(find_token(&conn, &secret).unwrap(), None);
+        assert_eq!(list_tokens(&conn, "bordeaux").unwrap(), vec![admin]);
+        assert!(matches!(revoke_token(&conn, "bordeaux", &presenter.id), Err(LotteryError::TokenNotFound { .. })));
+    }
+}
@@ -1,8 +1,71 @@
+table! {
+    api_tokens (id) {
+        id -> Text,
+        name -> Text,
+        token_hash -> Text,
+        role -> Text,
+        created_at -> Timestamp,
+        revoked -> Bool,
+        tenant -> Text,
+    }
+}
+
+table! {
+    draw_results (id) {
+        id -> Text,
+        draw_id -> Text,
+        position -> Integer,
+        first_name -> Text,
+        last_name -> Text,
+        winner_id -> Nullable<Text>,
+    }
+}
+
+table! {
+    draws (id) {
+        id -> Text,
+        event_id -> Text,
+        author -> Text,
+        seed -> Nullable<BigInt>,
+        prize -> Nullable<Text>,
+        drawn_at -> Timestamp,
+        tenant -> Text,
+    }
+}
+
+table! {
+    winner_audits (id) {
+        id -> Text,
+        winner_id -> Text,
+        action -> Text,
+        author -> Text,
+        previous_first_name -> Nullable<Text>,
+        previous_last_name -> Nullable<Text>,
+        first_name -> Nullable<Text>,
+        last_name -> Nullable<Text>,
+        changed_at -> Timestamp,
+    }
+}
+
 table! {
     winners (id) {
         id -> Text,
         first_name -> Text,
         last_name -> Text,
         event_id -> Text,
+        deleted -> Bool,
+        tenant -> Text,
     }
 }
+
+joinable!(draw_results -> draws (draw_id));
+joinable!(draw_results -> winners (winner_id));
+joinable!(winner_audits -> winners (winner_id));
+
+allow_tables_to_appear_in_same_query!(
+    api_tokens,
+    draw_results,
+    draws,
+    winner_audits,
+    winners,
+);
@@ -0,0 +1,139 @@
+//! Graceful shutdown on SIGINT/SIGTERM : the http server stops accepting requests, the refresh loops end,
+//! the database executors finish their pending writes, then the system stops
+use actix::actors::signal::{ProcessSignals, Signal, SignalType, Subscribe};
+use actix::prelude::*;
+use actix_web::server::StopServer;
+use database::{DbExecutor, Drain};
+use futures::future::{self, Future, Shared};
+use futures::sync::oneshot;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
+use std::time::{Duration, Instant};
+use tokio::timer::{self, Delay};
+
+/// Set once the shutdown started, the refresh loops end on their next tick or during their sleep
+#[derive(Clone)]
+pub struct ShutdownFlag {
+    set: Arc<AtomicBool>,
+    notify: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    stopped: Shared<oneshot::Receiver<()>>,
+}
+
+impl Default for ShutdownFlag {
+    fn default() -> Self {
+        let (notify, stopped) = oneshot::channel();
+        ShutdownFlag { set: Arc::new(AtomicBool::new(false)), notify: Arc::new(Mutex::new(Some(notify))), stopped: stopped.shared() }
+    }
+}
+
+impl ShutdownFlag {
+    pub fn is_set(&self) -> bool {
+        self.set.load(Ordering::SeqCst)
+    }
+
+    fn set(&self) {
+        self.set.store(true, Ordering::SeqCst);
+        if let Some(notify) = self.notify.lock().ok().and_then(|mut notify| notify.take()) {
+            let _ = notify.send(());
+        }
+    }
+
+    /// Wait for `duration`, or less when the shutdown starts meanwhile
+    pub fn sleep(&self, duration: Duration) -> impl Future<Item=(), Error=timer::Error> {
+        let stopped = self.stopped.clone().then(|_| Ok(()));
+        Delay::new(Instant::now() + duration)
+            .select(stopped)
+            .map(|_| ())
+            .map_err(|(error, _)| error)
+    }
+}
+
+pub struct Shutdown {
+    pub server: Recipient<StopServer>,
+    pub db: Addr<DbExecutor>,
+    pub executors: usize,
+    pub timeout: Duration,
+    pub flag: ShutdownFlag,
+}
+
+impl Shutdown {
+    /// Stop in order, the system is stopped anyway once the timeout is reached
+    fn shutdown(&mut self, ctx: &mut Context<Self>) {
+        if self.flag.is_set() {
+            return;
+        }
+        info!("Shutting down, waiting at most {}s for the pending requests", self.timeout.as_secs());
+        self.flag.set();
+        ctx.run_later(self.timeout, |_, _| {
+            warn!("Shutdown timeout reached, stopping now");
+            System::current().stop();
+        });
+
+        let db = self.db.clone();
+        let executors = self.executors;
+        let stopping = self.server.send(StopServer { graceful: true })
+            .then(move |_| {
+                info!("Http server stopped, waiting for the pending database writes");
+                let barrier = Arc::new(Barrier::new(executors));
+                future::join_all((0..executors).map(move |_| db.send(Drain { barrier: barrier.clone() })))
+            })
+            .then(|result| {
+                match result {
+                    Ok(_) => info!("Database writes done, stopping"),
+                    Err(error) => error!("Unable to wait for the database writes : {}", error),
+                }
+                System::current().stop();
+                Ok(())
+            });
+        ctx.spawn(stopping.into_actor(self));
+    }
+}
+
+impl Actor for Shutdown {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        System::current().registry().get::<ProcessSignals>().do_send(Subscribe(ctx.address().recipient()));
+    }
+}
+
+impl Handler<Signal> for Shutdown {
+    type Result = ();
+
+    fn handle(&mut self, msg: Signal, ctx: &mut Self::Context) {
+        match msg.0 {
+            SignalType::Int | SignalType::Term | SignalType::Quit => self.shutdown(ctx),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sleep_ends_on_shutdown() {
+        let mut system = System::new("test");
+        let flag = ShutdownFlag::default();
+        let started = Instant::now();
+
+        let setter = flag.clone();
+        let sleeping = flag.clone();
+        let slept = system.block_on(future::lazy(move || {
+            Arbiter::spawn(Delay::new(Instant::now() + Duration::from_millis(50)).then(move |_| {
+                setter.set();
+                Ok(())
+            }));
+            sleeping.sleep(Duration::from_secs(60))
+        }));
+        assert!(slept.is_ok());
+        assert!(flag.is_set());
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        // Already stopped, no sleep at all
+        let started = Instant::now();
+        assert!(system.block_on(flag.sleep(Duration::from_secs(60))).is_ok());
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
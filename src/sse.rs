@@ -0,0 +1,74 @@
+//! Server-Sent Events stream of the live events, for the screens behind proxies dropping WebSockets
+use actix_web::{error, http, HttpRequest, HttpResponse, FutureResponse, AsyncResponder};
+use actix_web::http::ContentEncoding;
+use broadcast::{Published, SubscribeStream};
+use bytes::Bytes;
+use serde_json;
+use std::time::{Duration, Instant};
+use tokio::prelude::{future, Future, Stream};
+use tokio::timer::Interval;
+use web::{request_tenant, WebState};
+use LotteryError;
+
+/// Comments are sent regularly so proxies don't close an idle connection
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+fn format_event(published: &Published) -> Result<String, serde_json::Error> {
+    serde_json::to_string(&published.event)
+        .map(|data| format!("id: {}\nevent: {}\ndata: {}\n\n", published.id, published.event.name(), data))
+}
+
+/// Id of the last event received by a reconnecting client, sent by browsers in the `Last-Event-ID` header
+fn last_event_id<S>(req: &HttpRequest<S>) -> Option<u64> {
+    req.headers().get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// Live events of the tenant of the request
+pub fn sse_handler(req: &HttpRequest<WebState>) -> FutureResponse<HttpResponse, LotteryError> {
+    let tenant = match request_tenant(req) {
+        Ok(tenant) => tenant,
+        Err(error) => return Box::new(future::err(error)),
+    };
+    tenant.broadcaster.send(SubscribeStream { last_event_id: last_event_id(req) })
+        .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
+        .map(|receiver| {
+            let events = receiver.expect("Subscription never fails")
+                .filter_map(|published| format_event(&published)
+                    .map_err(|error| error!("Unable to serialize live event {:?} : {}", published.event, error))
+                    .ok())
+                .map(Bytes::from)
+                .map_err(|_| error::ErrorInternalServerError("Live events stream closed"));
+            let keep_alive = Interval::new(Instant::now() + KEEP_ALIVE_INTERVAL, KEEP_ALIVE_INTERVAL)
+                .map(|_| Bytes::from_static(b":\n\n"))
+                .map_err(error::ErrorInternalServerError);
+
+            HttpResponse::Ok()
+                .content_type("text/event-stream")
+                .content_encoding(ContentEncoding::Identity)
+                .header(http::header::CACHE_CONTROL, "no-cache")
+                .streaming(events.select(keep_alive))
+        })
+        .responder()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use broadcast::LiveEvent;
+
+    #[test]
+    fn test_format_event() {
+        let published = Published { id: 12, event: LiveEvent::AttendeesUpdated { count: 42 } };
+        assert_eq!(format_event(&published).unwrap(), "id: 12\nevent: attendees_updated\ndata: {\"type\":\"attendees_updated\",\"count\":42}\n\n");
+    }
+
+    #[test]
+    fn test_last_event_id() {
+        assert_eq!(last_event_id(&TestRequest::with_header("Last-Event-ID", "42").finish()), Some(42));
+        assert_eq!(last_event_id(&TestRequest::with_header("Last-Event-ID", "abc").finish()), None);
+        assert_eq!(last_event_id(&TestRequest::default().finish()), None);
+    }
+}
@@ -0,0 +1,181 @@
+//! Tenants of the lottery : each chapter has its own eventbrite credentials, attendees cache, live events and winners
+use actix::Addr;
+use actix_web::HttpRequest;
+use broadcast::Broadcaster;
+use lotterycache::LotteryCache;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use LotteryError;
+
+/// Tenant of the `ORGANIZER_TOKEN`/`EVENTBRITE_TOKEN` pair, served on the routes without `/t/{tenant}`
+pub const DEFAULT_TENANT: &str = "default";
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum TenantError {
+    #[fail(display = "Invalid tenant name `{}`, expected lowercase letters, digits and dashes", name)]
+    InvalidName { name: String },
+    #[fail(display = "Tenant {} is declared twice", name)]
+    Duplicated { name: String },
+    #[fail(display = "{} is mandatory for the tenant {}", variable, name)]
+    MissingVariable { name: String, variable: String },
+    #[fail(display = "The organizer and the token of the tenant {} can't be empty", name)]
+    MissingCredentials { name: String },
+    #[fail(display = "The webhook secret of the tenant {} must have at least {} characters", name, min_length)]
+    WeakWebhookSecret { name: String, min_length: usize },
+    #[fail(display = "No tenant configured, set ORGANIZER_TOKEN and EVENTBRITE_TOKEN, TENANTS or a [[tenants]] section")]
+    NoTenant,
+}
+
+/// Eventbrite credentials of a tenant, the source of its attendees
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TenantConfig {
+    pub name: String,
+    pub organizer: String,
+    pub token: String,
+    /// Secret of the eventbrite webhook url, the webhook is disabled without it
+    #[serde(default)]
+    pub webhook_secret: Option<String>,
+}
+
+/// Minimum length of a webhook secret, it is the only check of the webhook requests
+const WEBHOOK_SECRET_MIN_LENGTH: usize = 16;
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// At least one tenant, with valid and unique names and credentials
+pub fn check_configs(configs: &[TenantConfig]) -> Result<(), TenantError> {
+    if configs.is_empty() {
+        return Err(TenantError::NoTenant);
+    }
+    for (index, config) in configs.iter().enumerate() {
+        if !is_valid_name(&config.name) {
+            return Err(TenantError::InvalidName { name: config.name.clone() });
+        }
+        if configs[..index].iter().any(|previous| previous.name == config.name) {
+            return Err(TenantError::Duplicated { name: config.name.clone() });
+        }
+        if config.organizer.trim().is_empty() || config.token.trim().is_empty() {
+            return Err(TenantError::MissingCredentials { name: config.name.clone() });
+        }
+        if config.webhook_secret.as_ref().is_some_and(|secret| secret.trim().len() < WEBHOOK_SECRET_MIN_LENGTH) {
+            return Err(TenantError::WeakWebhookSecret { name: config.name.clone(), min_length: WEBHOOK_SECRET_MIN_LENGTH });
+        }
+    }
+    Ok(())
+}
+
+/// Prefix of the variables of a tenant : `PAYS_BASQUE` for `pays-basque`
+fn env_prefix(name: &str) -> String {
+    name.to_uppercase().replace('-', "_")
+}
+
+/// Configuration of a tenant from the variables `<PREFIX>ORGANIZER_TOKEN`, `<PREFIX>EVENTBRITE_TOKEN` and the optional `<PREFIX>EVENTBRITE_WEBHOOK_SECRET`
+fn tenant_config<F: Fn(&str) -> Option<String>>(var: &F, name: &str, prefix: &str) -> Result<TenantConfig, TenantError> {
+    let mandatory = |variable: String| var(&variable)
+        .ok_or_else(|| TenantError::MissingVariable { name: name.to_owned(), variable });
+    Ok(TenantConfig {
+        name: name.to_owned(),
+        organizer: mandatory(format!("{}ORGANIZER_TOKEN", prefix))?,
+        token: mandatory(format!("{}EVENTBRITE_TOKEN", prefix))?,
+        webhook_secret: var(&format!("{}EVENTBRITE_WEBHOOK_SECRET", prefix)),
+    })
+}
+
+/// Tenants configured by the environment, read with `var` : the default tenant when `ORGANIZER_TOKEN` or `EVENTBRITE_TOKEN` is set,
+/// and the tenants listed in `TENANTS`, separated by commas, with their `<NAME>_ORGANIZER_TOKEN`, `<NAME>_EVENTBRITE_TOKEN` and `<NAME>_EVENTBRITE_WEBHOOK_SECRET`
+pub fn configs_from_env<F: Fn(&str) -> Option<String>>(var: F) -> Result<Vec<TenantConfig>, TenantError> {
+    let mut configs = Vec::new();
+    if var("ORGANIZER_TOKEN").is_some() || var("EVENTBRITE_TOKEN").is_some() {
+        configs.push(tenant_config(&var, DEFAULT_TENANT, "")?);
+    }
+    for name in var("TENANTS").unwrap_or_default().split(',').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+        if !is_valid_name(name) {
+            return Err(TenantError::InvalidName { name: name.to_owned() });
+        }
+        if configs.iter().any(|config: &TenantConfig| config.name == name) {
+            return Err(TenantError::Duplicated { name: name.to_owned() });
+        }
+        configs.push(tenant_config(&var, name, &format!("{}_", env_prefix(name)))?);
+    }
+    Ok(configs)
+}
+
+/// Name of the tenant of the `/t/{tenant}` routes, the default tenant on the other routes
+pub fn path_tenant<S>(req: &HttpRequest<S>) -> &str {
+    req.match_info().get("tenant").unwrap_or(DEFAULT_TENANT)
+}
+
+/// Actors of a running tenant
+#[derive(Clone)]
+pub struct Tenant {
+    pub name: String,
+    pub cache: Addr<LotteryCache>,
+    pub broadcaster: Addr<Broadcaster>,
+}
+
+/// Running tenants by name
+#[derive(Clone, Default)]
+pub struct Tenants(Arc<BTreeMap<String, Tenant>>);
+
+impl Tenants {
+    pub fn new(tenants: Vec<Tenant>) -> Tenants {
+        Tenants(Arc::new(tenants.into_iter().map(|tenant| (tenant.name.clone(), tenant)).collect()))
+    }
+
+    pub fn get(&self, name: &str) -> Result<Tenant, LotteryError> {
+        self.0.get(name).cloned().ok_or_else(|| LotteryError::TenantNotFound { name: name.to_owned() })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&Tenant> {
+        self.0.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::env;
+
+    fn config(name: &str, organizer: &str, token: &str) -> TenantConfig {
+        TenantConfig { name: name.to_string(), organizer: organizer.to_string(), token: token.to_string(), webhook_secret: None }
+    }
+
+    #[test]
+    fn test_configs_from_env() {
+        let single = env(&[("ORGANIZER_TOKEN", "1464915124"), ("EVENTBRITE_TOKEN", "5O5ICDI5")]);
+        assert_eq!(configs_from_env(single).unwrap(), vec![config("default", "1464915124", "5O5ICDI5")]);
+
+        let several = env(&[
+            ("TENANTS", "bordeaux, pays-basque"),
+            ("BORDEAUX_ORGANIZER_TOKEN", "1464915124"), ("BORDEAUX_EVENTBRITE_TOKEN", "5O5ICDI5"),
+            ("PAYS_BASQUE_ORGANIZER_TOKEN", "8719431297"), ("PAYS_BASQUE_EVENTBRITE_TOKEN", "LUFCAZRS"),
+            ("PAYS_BASQUE_EVENTBRITE_WEBHOOK_SECRET", "C4RK8GWJ5Y9N2Q7XTE3B"),
+        ]);
+        assert_eq!(configs_from_env(several).unwrap(), vec![
+            config("bordeaux", "1464915124", "5O5ICDI5"),
+            TenantConfig { webhook_secret: Some("C4RK8GWJ5Y9N2Q7XTE3B".to_string()), ..config("pays-basque", "8719431297", "LUFCAZRS") },
+        ]);
+
+        assert_eq!(configs_from_env(env(&[])), Ok(vec![]));
+        assert_eq!(configs_from_env(env(&[("ORGANIZER_TOKEN", "1464915124")])),
+                   Err(TenantError::MissingVariable { name: "default".to_string(), variable: "EVENTBRITE_TOKEN".to_string() }));
+        assert_eq!(configs_from_env(env(&[("TENANTS", "Bordeaux")])), Err(TenantError::InvalidName { name: "Bordeaux".to_string() }));
+        assert_eq!(configs_from_env(env(&[("TENANTS", "bordeaux,bordeaux"), ("BORDEAUX_ORGANIZER_TOKEN", "1"), ("BORDEAUX_EVENTBRITE_TOKEN", "2")])),
+                   Err(TenantError::Duplicated { name: "bordeaux".to_string() }));
+    }
+
+    #[test]
+    fn test_check_configs() {
+        assert_eq!(check_configs(&[config("bordeaux", "1464915124", "5O5ICDI5"), config("pays-basque", "8719431297", "LUFCAZRS")]), Ok(()));
+        assert_eq!(check_configs(&[]), Err(TenantError::NoTenant));
+        assert_eq!(check_configs(&[config("Bordeaux", "1464915124", "5O5ICDI5")]), Err(TenantError::InvalidName { name: "Bordeaux".to_string() }));
+        assert_eq!(check_configs(&[config("bordeaux", "1464915124", "5O5ICDI5"), config("bordeaux", "8719431297", "LUFCAZRS")]),
+                   Err(TenantError::Duplicated { name: "bordeaux".to_string() }));
+        assert_eq!(check_configs(&[config("bordeaux", "1464915124", " ")]), Err(TenantError::MissingCredentials { name: "bordeaux".to_string() }));
+        assert_eq!(check_configs(&[TenantConfig { webhook_secret: Some("secret".to_string()), ..config("bordeaux", "1464915124", "5O5ICDI5") }]),
+                   Err(TenantError::WeakWebhookSecret { name: "bordeaux".to_string(), min_length: 16 }));
+    }
+}
@@ -0,0 +1,20 @@
+//! Helpers shared by the tests of several modules
+use eventbrite::{Attende, Profile};
+use std::collections::HashMap;
+
+/// Environment of the given variables only
+pub fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+    let vars: HashMap<String, String> = vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+    move |name| vars.get(name).cloned()
+}
+
+/// Attendee of the event 52097259305, neither cancelled nor refunded
+pub fn attendee(id: &str, first_name: &str, last_name: &str) -> Attende {
+    Attende {
+        id: id.to_string(),
+        event_id: "52097259305".to_string(),
+        profile: Profile { first_name: first_name.to_string(), last_name: last_name.to_string() },
+        cancelled: false,
+        refunded: false,
+    }
+}
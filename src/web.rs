@@ -1,77 +1,563 @@
-use actix::prelude::Addr;
-use actix_web::{App, HttpResponse, FutureResponse, State, AsyncResponder, Query, Json};
-use actix_web::{http, error, middleware};
-use actix_web::server::HttpServer;
+use actix::prelude::{Addr, Recipient};
+use actix_web::{App, HttpRequest, HttpResponse, FromRequest, FutureResponse, State, AsyncResponder, Query, Json, Path};
+use actix_web::{http, error, middleware, ws};
+use actix_web::server::{HttpServer, StopServer};
+use actix_web::middleware::cors::{Cors, CorsBuilder};
 use LotteryError;
 use tokio::prelude::{future, Future};
-use lotterycache::{GetAttendees, GetEvent, LotteryCache};
-use database::{CreateWinner, DbExecutor};
+use lotterycache::{ApplyWebhook, CacheStatus, FindAttendee, GetAttendees, GetCacheStatus, GetEvent, GetSummary, Refresh};
+use eventbrite::{is_api_url, WebhookPayload};
+use chrono::{Duration, Utc};
+use lottery::DrawRequest;
+use rand::{thread_rng, Rng};
+use repository::Winner;
+use schemars::JsonSchema;
+use database::{CreateDraw, CreateWinner, IsDrawn, RecordDrawResult, UpdateWinner, DeleteWinner, GetSchemaVersion, CreateToken, ListTokens, FindToken, RevokeToken, DbExecutor};
+use auth::{constant_time_eq, ApiKeys, AuthState, Identity, RequireRole, Role};
+use broadcast::{LiveEvent, Publish};
+use websocket::WsSession;
+use sse::sse_handler;
+use metrics::{Metrics, RequestMetrics};
+use api_error::{invalid_request, RequestId};
+use openapi;
+use tenant::{path_tenant, Tenant, Tenants};
+use reload::{Reload, Reloader};
+use config::SharedConfig;
+use webhook::{LoadWebhookAttendees, WebhookLoader};
+use std::time::Instant;
 
 #[derive(Clone)]
 pub struct WebState {
-    pub cache: Addr<LotteryCache>,
+    pub tenants: Tenants,
     pub db: Addr<DbExecutor>,
+    pub api_keys: ApiKeys,
+    /// Age of the last refresh from eventbrite after which the server is not ready anymore
+    pub cache_max_age: Duration,
+    pub metrics: Metrics,
+    /// Maximum number of winners of a draw
+    pub max_draw: usize,
+    pub reloader: Addr<Reloader>,
+    /// Current eventbrite credentials of the tenants
+    pub config: SharedConfig,
+    pub webhooks: Addr<WebhookLoader>,
 }
 
-impl error::ResponseError for LotteryError {
-    fn error_response(&self) -> HttpResponse {
-        match *self {
-            LotteryError::InvalidParameter => HttpResponse::new(http::StatusCode::BAD_REQUEST),
-            LotteryError::NoEventAvailable => HttpResponse::with_body(http::StatusCode::SERVICE_UNAVAILABLE, "No event available on eventbrite"),
-            LotteryError::DrawError { cause: ref e } => HttpResponse::with_body(http::StatusCode::BAD_REQUEST, format!("{}", e)),
-            LotteryError::UnexpectedError { cause: ref e } => HttpResponse::with_body(http::StatusCode::INTERNAL_SERVER_ERROR, format!("{}", e))
-        }
+impl AuthState for WebState {
+    fn api_keys(&self) -> &ApiKeys {
+        &self.api_keys
+    }
+
+    fn find_token(&self, token: &str) -> Box<dyn Future<Item=Option<Identity>, Error=LotteryError>> {
+        Box::new(self.db.send(FindToken { secret: token.to_owned() })
+            .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
+            .and_then(|result| result)
+            .map(|token| token.map(|token| Identity { name: token.name, role: token.role, tenant: Some(token.tenant) })))
+    }
+}
+
+/// Name of the person making a change, set by the `RequireRole` middleware
+struct Author(String);
+
+impl<S> FromRequest<S> for Author {
+    type Config = ();
+    type Result = Author;
+
+    fn from_request(req: &HttpRequest<S>, _cfg: &Self::Config) -> Self::Result {
+        Author(req.extensions().get::<Identity>()
+            .map(|identity| identity.name.clone())
+            .unwrap_or_else(|| "anonymous".to_owned()))
+    }
+}
+
+/// Tenant of the `/t/{tenant}` routes, the default tenant on the other routes
+pub fn request_tenant(req: &HttpRequest<WebState>) -> Result<Tenant, LotteryError> {
+    req.state().tenants.get(path_tenant(req))
+}
+
+impl FromRequest<WebState> for Tenant {
+    type Config = ();
+    type Result = Result<Tenant, error::Error>;
+
+    fn from_request(req: &HttpRequest<WebState>, _cfg: &Self::Config) -> Self::Result {
+        request_tenant(req).map_err(|error| error.into())
     }
 }
 
+/// Id of the routes ending with `/{id}`, the tenant may also be in the path
 #[derive(Deserialize)]
-struct WinnerQuery {
-    nb: i8
+struct IdPath {
+    id: String,
 }
 
-fn winner_handler((state, query): (State<WebState>, Query<WinnerQuery>)) -> FutureResponse<HttpResponse, LotteryError> {
-    match query.nb {
-        nb if nb < 0 => Box::new(future::err(LotteryError::InvalidParameter)),
-        _ => state.cache.send(GetAttendees { nb: query.nb })
+/// Role of the token of the request, set by the `RequireRole` middleware
+struct CallerRole(Option<Role>);
+
+impl<S> FromRequest<S> for CallerRole {
+    type Config = ();
+    type Result = CallerRole;
+
+    fn from_request(req: &HttpRequest<S>, _cfg: &Self::Config) -> Self::Result {
+        CallerRole(req.extensions().get::<Identity>().map(|identity| identity.role))
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct WinnerQuery {
+    /// Number of winners to draw
+    pub nb: usize,
+    /// Full names of the attendees who can't win, separated by commas
+    pub exclude: Option<String>,
+    /// Seed of the draw, to replay it
+    pub seed: Option<u64>,
+    /// What the winners get, shown on the screens
+    pub prize: Option<String>,
+}
+
+impl WinnerQuery {
+    fn into_draw_request(self, max_draw: usize) -> Result<DrawRequest, LotteryError> {
+        if self.nb > max_draw {
+            return Err(LotteryError::InvalidParameter { name: "nb".to_owned(), reason: format!("At most {} winners can be drawn at once", max_draw) });
+        }
+        Ok(DrawRequest {
+            nb: self.nb,
+            excluded: self.exclude.iter()
+                .flat_map(|names| names.split(','))
+                .map(|name| name.trim().to_owned())
+                .filter(|name| !name.is_empty())
+                .collect(),
+            seed: self.seed,
+            prize: self.prize.filter(|prize| !prize.trim().is_empty()),
+        })
+    }
+}
+
+fn winner_handler((state, query, tenant): (State<WebState>, Query<WinnerQuery>, Tenant)) -> FutureResponse<HttpResponse, LotteryError> {
+    match query.into_inner().into_draw_request(state.max_draw) {
+        Err(error) => Box::new(future::err(error)),
+        Ok(request) => tenant.cache.send(GetAttendees { request })
             .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
             .and_then(|result| result)
-            .and_then(|res| Ok(HttpResponse::Ok().json(res)))
+            .and_then(|drawn| Ok(HttpResponse::Ok().json(drawn.winners)))
             .responder()
     }
 }
 
+/// Draw winners and keep the draw, its results can then be recorded
+fn create_draw_handler((query, author, tenant, state): (Json<WinnerQuery>, Author, Tenant, State<WebState>)) -> FutureResponse<HttpResponse, LotteryError> {
+    let mut request = match query.into_inner().into_draw_request(state.max_draw) {
+        Ok(request) => request,
+        Err(error) => return Box::new(future::err(error)),
+    };
+    // Persisted draws always have a seed, so they can be replayed
+    request.seed = request.seed.or_else(|| Some(thread_rng().gen()));
+    let (seed, prize) = (request.seed, request.prize.clone());
+    let db = state.db.clone();
+    tenant.cache.send(GetAttendees { request })
+        .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
+        .and_then(|result| result)
+        .and_then(move |drawn| db.send(CreateDraw {
+            tenant: tenant.name,
+            event_id: drawn.event_id.unwrap_or_else(|| "Unknown".to_owned()),
+            author: author.0,
+            seed,
+            prize,
+            winners: drawn.winners,
+        }).map_err(|error| LotteryError::UnexpectedError { cause: error.into() }))
+        .and_then(|result| result)
+        .and_then(|draw| Ok(HttpResponse::Created().json(draw)))
+        .responder()
+}
+
+/// Body of `POST /record` : a result of a draw, or for admins only the name of a winner drawn outside of the api
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum RecordWinner {
+    DrawResult { draw_result_id: String },
+    Named(CreateWinner),
+}
+
+/// Presenters record the results of their draws, only admins can name a winner
+fn check_named_record(role: Option<Role>) -> Result<(), LotteryError> {
+    match role {
+        Some(Role::Admin) => Ok(()),
+        _ => Err(LotteryError::NotAllowed { reason: "Only an admin can record a winner by name, record the result of a draw with its draw_result_id".to_owned() }),
+    }
+}
+
+/// Record a named winner if they are an attendee of the current event or were drawn, unless the admin forces it
+fn record_named_winner(state: &WebState, tenant: &Tenant, mut winner: CreateWinner, role: Option<Role>) -> Box<dyn Future<Item=Winner, Error=LotteryError>> {
+    if let Err(error) = check_named_record(role) {
+        return Box::new(future::err(error));
+    }
+    let db = state.db.clone();
+    winner.tenant = tenant.name.clone();
+    let attendee = FindAttendee { first_name: winner.first_name.clone(), last_name: winner.last_name.clone() };
+    Box::new(tenant.cache.send(attendee).join(tenant.cache.send(GetEvent {}))
+        .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
+        .and_then(move |(is_attendee, event)| {
+            winner.event_id = event.map(|event| event.id).ok();
+            let known: Box<dyn Future<Item=bool, Error=LotteryError>> = if is_attendee || winner.force {
+                Box::new(future::ok(is_attendee))
+            } else {
+                Box::new(db.send(IsDrawn { tenant: winner.tenant.clone(), event_id: winner.event_id.clone(), first_name: winner.first_name.clone(), last_name: winner.last_name.clone() })
+                    .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
+                    .and_then(|result| result))
+            };
+            known.and_then(move |known| {
+                if !known && !winner.force {
+                    return future::Either::A(future::err(LotteryError::UnknownAttendee { first_name: winner.first_name, last_name: winner.last_name }));
+                }
+                if !known {
+                    warn!("Forced record of {} {}, who is neither an attendee nor drawn", winner.first_name, winner.last_name);
+                }
+                future::Either::B(db.send(winner)
+                    .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
+                    .and_then(|result| result))
+            })
+        }))
+}
+
 /// Async request handler
 fn record_winner_handler(
-    (winner, state): (Json<CreateWinner>, State<WebState>),
-) -> FutureResponse<HttpResponse> {
-    state.cache.send(GetEvent {})
-        .and_then(move |event| {
-            let mut winner = winner.into_inner();
-            winner.event_id = event.map(|event| event.id).ok();
-            state.db.send(winner)
+    (body, role, tenant, state): (Json<RecordWinner>, CallerRole, Tenant, State<WebState>),
+) -> FutureResponse<HttpResponse, LotteryError> {
+    let recorded: Box<dyn Future<Item=Winner, Error=LotteryError>> = match body.into_inner() {
+        RecordWinner::DrawResult { draw_result_id } => Box::new(state.db.send(RecordDrawResult { tenant: tenant.name.clone(), id: draw_result_id })
+            .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
+            .and_then(|result| result)),
+        RecordWinner::Named(winner) => record_named_winner(&state, &tenant, winner, role.0),
+    };
+    recorded
+        .and_then(move |winner| {
+            state.metrics.winner_recorded(&tenant.name);
+            tenant.broadcaster.do_send(Publish(LiveEvent::Recorded { winner: winner.clone() }));
+            Ok(HttpResponse::Ok().json(winner))
+        })
+        .responder()
+}
+
+fn update_winner_handler(
+    (path, winner, author, tenant, state): (Path<IdPath>, Json<UpdateWinner>, Author, Tenant, State<WebState>),
+) -> FutureResponse<HttpResponse, LotteryError> {
+    let mut winner = winner.into_inner();
+    winner.tenant = tenant.name;
+    winner.id = path.into_inner().id;
+    winner.author = author.0;
+    state.db.send(winner)
+        .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
+        .and_then(|result| result)
+        .and_then(|winner| Ok(HttpResponse::Ok().json(winner)))
+        .responder()
+}
+
+fn delete_winner_handler(
+    (path, author, tenant, state): (Path<IdPath>, Author, Tenant, State<WebState>),
+) -> FutureResponse<HttpResponse, LotteryError> {
+    state.db.send(DeleteWinner { tenant: tenant.name.clone(), id: path.into_inner().id, author: author.0 })
+        .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
+        .and_then(|result| result)
+        .and_then(move |winner| {
+            tenant.broadcaster.do_send(Publish(LiveEvent::Forfeited { winner }));
+            Ok(HttpResponse::NoContent().finish())
+        })
+        .responder()
+}
+
+fn list_tokens_handler((tenant, state): (Tenant, State<WebState>)) -> FutureResponse<HttpResponse, LotteryError> {
+    state.db.send(ListTokens { tenant: tenant.name })
+        .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
+        .and_then(|result| result)
+        .and_then(|tokens| Ok(HttpResponse::Ok().json(tokens)))
+        .responder()
+}
+
+fn create_token_handler((token, tenant, state): (Json<CreateToken>, Tenant, State<WebState>)) -> FutureResponse<HttpResponse, LotteryError> {
+    let mut token = token.into_inner();
+    token.tenant = tenant.name;
+    state.db.send(token)
+        .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
+        .and_then(|result| result)
+        .and_then(|token| Ok(HttpResponse::Created().json(token)))
+        .responder()
+}
+
+fn revoke_token_handler((path, tenant, state): (Path<IdPath>, Tenant, State<WebState>)) -> FutureResponse<HttpResponse, LotteryError> {
+    state.db.send(RevokeToken { tenant: tenant.name, id: path.into_inner().id })
+        .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
+        .and_then(|result| result)
+        .and_then(|_| Ok(HttpResponse::NoContent().finish()))
+        .responder()
+}
+
+#[derive(Deserialize)]
+pub struct WebhookQuery {
+    secret: Option<String>,
+}
+
+/// Eventbrite webhook of the tenant, authenticated by the secret of its url. The changed attendees are loaded
+/// from the eventbrite api and applied to the cache, the refreshes still run in case a notification is lost
+fn eventbrite_webhook_handler((tenant, query, payload, state): (Tenant, Query<WebhookQuery>, Json<WebhookPayload>, State<WebState>)) -> FutureResponse<HttpResponse, LotteryError> {
+    let credentials = match state.config.tenant(&tenant.name) {
+        Some(credentials) => credentials,
+        None => return Box::new(future::err(LotteryError::TenantNotFound { name: tenant.name })),
+    };
+    let verified = match (credentials.webhook_secret.as_ref(), query.secret.as_ref()) {
+        (Some(expected), Some(secret)) if constant_time_eq(expected.as_bytes(), secret.as_bytes()) => Ok(()),
+        (None, _) => Err(LotteryError::NotAllowed { reason: format!("Webhooks are not enabled for the tenant {}", tenant.name) }),
+        _ => Err(LotteryError::NotAllowed { reason: "Invalid webhook secret".to_owned() }),
+    };
+    if let Err(error) = verified {
+        warn!("[{}] Webhook rejected : {}", tenant.name, error);
+        return Box::new(future::err(error));
+    }
+    let payload = payload.into_inner();
+    if !is_api_url(&payload.api_url) {
+        return Box::new(future::err(LotteryError::InvalidRequest { details: format!("api_url is not an url of the eventbrite api : {}", payload.api_url) }));
+    }
+    let (name, cache, action) = (tenant.name.clone(), tenant.cache.clone(), payload.config.action.clone());
+    state.webhooks.send(LoadWebhookAttendees { payload, token: credentials.token })
+        .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
+        .and_then(|result| result)
+        .and_then(move |changed| cache.send(ApplyWebhook { action, changed })
+            .map_err(|error| LotteryError::UnexpectedError { cause: error.into() }))
+        .and_then(move |outcome| {
+            info!("[{}] Webhook {} applied to {} attendee(s)", name, outcome.action, outcome.applied);
+            Ok(HttpResponse::Ok().json(outcome))
         })
-        .from_err()
-        .and_then(|res| match res {
-            Ok(user) => Ok(HttpResponse::Ok().json(user)),
-            Err(_) => Ok(HttpResponse::InternalServerError().into()),
+        .responder()
+}
+
+/// Refresh the attendees of the tenant now, the refreshes started since the request are not repeated
+fn refresh_handler((tenant, state): (Tenant, State<WebState>)) -> FutureResponse<HttpResponse, LotteryError> {
+    let credentials = match state.config.tenant(&tenant.name) {
+        Some(credentials) => credentials,
+        None => return Box::new(future::err(LotteryError::TenantNotFound { name: tenant.name })),
+    };
+    tenant.cache.send(Refresh { organizer: credentials.organizer, token: credentials.token, requested_at: Instant::now() })
+        .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
+        .and_then(|outcome| Ok(HttpResponse::Ok().json(outcome)))
+        .responder()
+}
+
+fn reload_handler(state: State<WebState>) -> FutureResponse<HttpResponse, LotteryError> {
+    state.reloader.send(Reload)
+        .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
+        .and_then(|result| result.map_err(|error| LotteryError::InvalidConfiguration { details: error.to_string() }))
+        .and_then(|reloaded| Ok(HttpResponse::Ok().json(reloaded)))
+        .responder()
+}
+
+/// Presentation view for the projector, embedded in the binary
+const PRESENTATION_PAGE: &str = include_str!("../static/presentation.html");
+
+/// Page of the tenant, its api is called with relative urls
+fn presentation_handler(req: &HttpRequest<WebState>) -> Result<HttpResponse, LotteryError> {
+    request_tenant(req).map(|_| HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(PRESENTATION_PAGE))
+}
+
+/// `/t/{tenant}` without the trailing slash would resolve the relative urls of the page outside of the tenant
+fn tenant_redirect_handler(req: &HttpRequest<WebState>) -> HttpResponse {
+    HttpResponse::MovedPermanently()
+        .header(http::header::LOCATION, format!("{}/", req.path()))
+        .finish()
+}
+
+fn summary_handler(tenant: Tenant) -> FutureResponse<HttpResponse, LotteryError> {
+    tenant.cache.send(GetSummary)
+        .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
+        .and_then(|summary| Ok(HttpResponse::Ok().json(summary)))
+        .responder()
+}
+
+/// Live events for the screens
+fn ws_handler(req: &HttpRequest<WebState>) -> Result<HttpResponse, error::Error> {
+    let tenant = request_tenant(req)?;
+    ws::start(req, WsSession::new(tenant.broadcaster))
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct Status {
+    /// Last migration applied on the database
+    pub schema_version: Option<String>,
+    #[serde(flatten)]
+    pub cache: CacheStatus,
+}
+
+fn metrics_handler(req: &HttpRequest<WebState>) -> Result<HttpResponse, LotteryError> {
+    req.state().metrics.render()
+        .map(|metrics| HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(metrics))
+}
+
+/// The process is up
+fn health_handler(_req: &HttpRequest<WebState>) -> HttpResponse {
+    HttpResponse::Ok().json(Health { status: "up" })
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct Health {
+    pub status: &'static str
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct Readiness {
+    pub database: bool,
+    pub cache: bool,
+}
+
+/// The database answers and the attendees of every tenant were refreshed recently, `503` otherwise
+fn ready_handler(state: State<WebState>) -> FutureResponse<HttpResponse, LotteryError> {
+    let max_age = state.cache_max_age;
+    let caches = state.tenants.iter().map(|tenant| tenant.cache.send(GetCacheStatus)).collect::<Vec<_>>();
+    state.db.send(GetSchemaVersion)
+        .map(|result| result.is_ok())
+        .join(future::join_all(caches).map(move |statuses| statuses.iter().all(|status| status.is_ready(Utc::now(), max_age))))
+        .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
+        .and_then(|(database, cache)| {
+            let readiness = Readiness { database, cache };
+            Ok(if database && cache {
+                HttpResponse::Ok().json(readiness)
+            } else {
+                HttpResponse::ServiceUnavailable().json(readiness)
+            })
         })
         .responder()
 }
 
-pub fn http_server(state: WebState, http_bind: String, http_port: String) {
-    use actix_web::middleware::cors::Cors;
-    HttpServer::new(move ||
+fn status_handler((tenant, state): (Tenant, State<WebState>)) -> FutureResponse<HttpResponse, LotteryError> {
+    state.db.send(GetSchemaVersion)
+        .join(tenant.cache.send(GetCacheStatus))
+        .map_err(|error| LotteryError::UnexpectedError { cause: error.into() })
+        .and_then(|(schema_version, cache)| Ok(HttpResponse::Ok().json(Status { schema_version: schema_version?, cache })))
+        .responder()
+}
+
+/// Current version of the api, its routes are also served without prefix for the existing clients
+pub const API_PREFIX: &str = "/api/v1";
+
+/// Prefix of the routes of a named tenant, the routes without it are the ones of the default tenant
+pub const TENANT_PREFIX: &str = "/t/{tenant}";
+
+fn openapi_handler(_req: &HttpRequest<WebState>) -> HttpResponse {
+    HttpResponse::Ok().json(openapi::document())
+}
+
+/// Register the routes of a tenant under `prefix`
+fn tenant_resources<'a>(cors: &'a mut CorsBuilder<WebState>, prefix: &str) -> &'a mut CorsBuilder<WebState> {
+    cors.resource(&format!("{}/winners", prefix), |r| {
+            r.middleware(RequireRole(Role::Presenter));
+            r.method(http::Method::GET).with_config(winner_handler, |cfg| { (cfg.0).1.error_handler(invalid_request); });
+        })
+        .resource(&format!("{}/summary", prefix), |r| r.method(http::Method::GET).with(summary_handler))
+        .resource(&format!("{}/status", prefix), |r| r.method(http::Method::GET).with(status_handler))
+        .resource(&format!("{}/ws", prefix), |r| r.method(http::Method::GET).f(ws_handler))
+        .resource(&format!("{}/events", prefix), |r| r.method(http::Method::GET).f(sse_handler))
+        .resource(&format!("{}/webhooks/eventbrite", prefix), |r| {
+            r.method(http::Method::POST).with_config(eventbrite_webhook_handler, |cfg| {
+                (cfg.0).1.error_handler(invalid_request);
+                (cfg.0).2.error_handler(invalid_request);
+            });
+        })
+        .resource(&format!("{}/admin/tokens", prefix), |r| {
+            r.middleware(RequireRole(Role::Admin));
+            r.method(http::Method::GET).with(list_tokens_handler);
+            r.method(http::Method::POST).with_config(create_token_handler, |cfg| { (cfg.0).0.error_handler(invalid_request); });
+        })
+        .resource(&format!("{}/admin/tokens/{{id}}", prefix), |r| {
+            r.middleware(RequireRole(Role::Admin));
+            r.method(http::Method::DELETE).with(revoke_token_handler);
+        })
+        .resource(&format!("{}/admin/refresh", prefix), |r| {
+            r.middleware(RequireRole(Role::Admin));
+            r.method(http::Method::POST).with(refresh_handler);
+        })
+        .resource(&format!("{}/draws", prefix), |r| {
+            r.middleware(RequireRole(Role::Presenter));
+            r.method(http::Method::POST).with_config(create_draw_handler, |cfg| { (cfg.0).0.error_handler(invalid_request); });
+        })
+        .resource(&format!("{}/record", prefix), |r| {
+            r.middleware(RequireRole(Role::Presenter));
+            r.method(http::Method::POST).with_config(record_winner_handler, |cfg| { (cfg.0).0.error_handler(invalid_request); });
+        })
+        .resource(&format!("{}/record/{{id}}", prefix), |r| {
+            r.middleware(RequireRole(Role::Admin));
+            r.method(http::Method::PUT).with_config(update_winner_handler, |cfg| { (cfg.0).1.error_handler(invalid_request); });
+            r.method(http::Method::DELETE).with(delete_winner_handler);
+        })
+}
+
+/// Register the api routes under `prefix` : the routes of the default tenant, the reload and the OpenAPI document
+fn api_resources<'a>(cors: &'a mut CorsBuilder<WebState>, prefix: &str) -> &'a mut CorsBuilder<WebState> {
+    tenant_resources(cors, prefix)
+        .resource(&format!("{}/openapi.json", prefix), |r| r.method(http::Method::GET).f(openapi_handler))
+        .resource(&format!("{}/admin/reload", prefix), |r| {
+            r.middleware(RequireRole(Role::Admin));
+            r.method(http::Method::POST).with(reload_handler);
+        })
+}
+
+/// Start the http server, `allowed_origins` restricts CORS requests when not empty.
+///
+/// Access per route : draws and records need a presenter token, history edits and
+/// tokens management, refreshes and reloads an admin token, the eventbrite webhooks their secret,
+/// the presentation page, health checks, status, metrics and live events are public.
+pub fn http_server(state: WebState, http_bind: String, http_port: String, allowed_origins: Vec<String>, shutdown_timeout: u16) -> Recipient<StopServer> {
+    HttpServer::new(move || {
+        let allowed_origins = allowed_origins.clone();
         App::with_state(state.clone())
             .middleware(middleware::Logger::default())
-            .configure(|app| Cors::for_app(app) // <- Construct CORS middleware builder
-                .allowed_methods(vec!["GET", "POST", "OPTION"])
-                .allowed_headers(vec![http::header::AUTHORIZATION, http::header::ACCEPT])
-                .allowed_header(http::header::CONTENT_TYPE)
-                .max_age(3600)
-                .resource("/winners", |r| r.method(http::Method::GET).with(winner_handler))
-                .resource("/record", |r| r.method(http::Method::POST).with(record_winner_handler))
-                .register()))
+            .middleware(RequestId)
+            .middleware(RequestMetrics(state.metrics.clone()))
+            .configure(move |app| {
+                let mut cors = Cors::for_app(app); // <- Construct CORS middleware builder
+                allowed_origins.iter().for_each(|origin| { cors.allowed_origin(origin); });
+                cors.allowed_methods(vec!["GET", "POST", "PUT", "DELETE", "OPTION"])
+                    .allowed_headers(vec![http::header::AUTHORIZATION, http::header::ACCEPT])
+                    .allowed_header(http::header::CONTENT_TYPE)
+                    .max_age(3600)
+                    .resource("/", |r| r.method(http::Method::GET).f(presentation_handler))
+                    .resource("/health", |r| r.method(http::Method::GET).f(health_handler))
+                    .resource("/ready", |r| r.method(http::Method::GET).with(ready_handler))
+                    .resource("/metrics", |r| r.method(http::Method::GET).f(metrics_handler));
+                api_resources(&mut cors, API_PREFIX);
+                api_resources(&mut cors, "");
+                cors.resource(TENANT_PREFIX, |r| r.method(http::Method::GET).f(tenant_redirect_handler))
+                    .resource(&format!("{}/", TENANT_PREFIX), |r| r.method(http::Method::GET).f(presentation_handler));
+                tenant_resources(&mut cors, &format!("{}{}", TENANT_PREFIX, API_PREFIX));
+                tenant_resources(&mut cors, TENANT_PREFIX);
+                cors.register()
+            })
+    })
+        .disable_signals()
+        .shutdown_timeout(shutdown_timeout)
         .bind(format!("{}:{}", http_bind, http_port))
         .unwrap()
-        .start();
-}
\ No newline at end of file
+        .start()
+        .recipient()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(nb: usize, exclude: Option<&str>) -> WinnerQuery {
+        WinnerQuery { nb, exclude: exclude.map(|exclude| exclude.to_string()), seed: Some(42), prize: Some(" ".to_string()) }
+    }
+
+    #[test]
+    fn test_into_draw_request() {
+        let request = query(3, Some("Francois Teychene, ,Fabien Bernard ")).into_draw_request(50).unwrap();
+        assert_eq!(request, DrawRequest { nb: 3, excluded: vec!["Francois Teychene".to_string(), "Fabien Bernard".to_string()], seed: Some(42), prize: None });
+
+        assert!(query(50, None).into_draw_request(50).is_ok());
+        assert!(matches!(query(51, None).into_draw_request(50), Err(LotteryError::InvalidParameter { ref name, .. }) if name == "nb"));
+    }
+
+    #[test]
+    fn test_check_named_record() {
+        assert!(check_named_record(Some(Role::Admin)).is_ok());
+        assert!(matches!(check_named_record(Some(Role::Presenter)), Err(LotteryError::NotAllowed { .. })));
+        assert!(matches!(check_named_record(None), Err(LotteryError::NotAllowed { .. })));
+    }
+}
@@ -0,0 +1,38 @@
+//! Webhook loader actor
+use actix::prelude::*;
+use actix::SyncArbiter;
+use eventbrite::{Attende, WebhookPayload, load_webhook_attendees, redact_token};
+use LotteryError;
+
+/// Number of webhook notifications loaded in parallel
+const LOADERS: usize = 2;
+
+/// Loads the attendees of the eventbrite webhooks out of the event loop, the requests to eventbrite are blocking
+pub struct WebhookLoader;
+
+impl Actor for WebhookLoader {
+    type Context = SyncContext<Self>;
+}
+
+/// Load the attendees changed by a webhook notification with the token of its tenant
+pub struct LoadWebhookAttendees {
+    pub payload: WebhookPayload,
+    pub token: String,
+}
+
+impl Message for LoadWebhookAttendees {
+    type Result = Result<Option<Vec<Attende>>, LotteryError>;
+}
+
+impl Handler<LoadWebhookAttendees> for WebhookLoader {
+    type Result = Result<Option<Vec<Attende>>, LotteryError>;
+
+    fn handle(&mut self, msg: LoadWebhookAttendees, _: &mut Self::Context) -> Self::Result {
+        load_webhook_attendees(&msg.payload, &msg.token)
+            .map_err(|error| LotteryError::UnexpectedError { cause: format_err!("{}", redact_token(&error.to_string(), &msg.token)) })
+    }
+}
+
+pub fn start_webhook_loader() -> Addr<WebhookLoader> {
+    SyncArbiter::start(LOADERS, || WebhookLoader)
+}
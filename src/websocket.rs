@@ -0,0 +1,61 @@
+//! WebSocket session forwarding the live events to a connected screen
+use actix::{Actor, ActorContext, AsyncContext, Addr, Handler, StreamHandler};
+use actix_web::ws;
+use broadcast::{Broadcaster, Published, Subscribe};
+use serde_json;
+use std::time::{Duration, Instant};
+use web::WebState;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(15);
+
+pub struct WsSession {
+    broadcaster: Addr<Broadcaster>,
+    last_heartbeat: Instant,
+}
+
+impl WsSession {
+    pub fn new(broadcaster: Addr<Broadcaster>) -> WsSession {
+        WsSession { broadcaster, last_heartbeat: Instant::now() }
+    }
+}
+
+impl Actor for WsSession {
+    type Context = ws::WebsocketContext<Self, WebState>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.broadcaster.do_send(Subscribe(ctx.address().recipient()));
+        // Screens on flaky networks disappear without closing the connection
+        ctx.run_interval(HEARTBEAT_INTERVAL, |session, ctx| {
+            if Instant::now().duration_since(session.last_heartbeat) > CLIENT_TIMEOUT {
+                info!("Websocket client timed out");
+                ctx.stop();
+            } else {
+                ctx.ping("");
+            }
+        });
+    }
+}
+
+impl Handler<Published> for WsSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Published, ctx: &mut Self::Context) {
+        match serde_json::to_string(&msg.event) {
+            Ok(json) => ctx.text(json),
+            Err(error) => error!("Unable to serialize live event {:?} : {}", msg.event, error),
+        }
+    }
+}
+
+impl StreamHandler<ws::Message, ws::ProtocolError> for WsSession {
+    fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
+        self.last_heartbeat = Instant::now();
+        match msg {
+            ws::Message::Ping(message) => ctx.pong(&message),
+            ws::Message::Close(_) => ctx.stop(),
+            // Screens only listen, anything else they send is ignored
+            _ => (),
+        }
+    }
+}